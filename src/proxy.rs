@@ -1,12 +1,115 @@
 use async_http_proxy::{http_connect_tokio, http_connect_tokio_with_basic_auth, HttpError};
 use derive_builder::Builder;
 use fast_socks5::client::{Config as Socks5Config, Socks5Stream};
-use fast_socks5::SocksError;
+use fast_socks5::util::target_addr::TargetAddr;
+use fast_socks5::{AuthenticationMethod, Socks5Command, SocksError};
+use percent_encoding::percent_decode_str;
+use std::fmt;
+use std::io::{self, IoSlice};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
 use std::time::Duration;
 use thiserror::Error;
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{lookup_host, TcpStream};
 use url::{ParseError, Url};
 
+mod chain;
+mod pool;
+mod session;
+mod socks4;
+pub(crate) mod socks5;
+mod tls;
+
+pub use chain::ProxyChain;
+pub use pool::{
+    BalanceStrategy, Candidate, LeastConnections, PoolLease, ProxyPool, ProxyPoolBuilder, Random,
+    RoundRobin, Weighted,
+};
+pub use session::{Rotation, SessionParams};
+pub use socks4::Socks4Error;
+pub use socks5::{PendingBind, UdpAssociation};
+pub use tls::{TlsOptions, TlsOptionsBuilder};
+
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T> AsyncStream for T where T: AsyncRead + AsyncWrite + Unpin + Send {}
+
+/// A stream tunneled through the upstream proxy
+pub struct ProxyStream {
+    inner: Box<dyn AsyncStream>,
+    bound_addr: Option<TargetAddr>,
+}
+
+impl ProxyStream {
+    pub fn new(stream: impl AsyncStream + 'static) -> Self {
+        Self {
+            inner: Box::new(stream),
+            bound_addr: None,
+        }
+    }
+
+    /// The bound address reported by the proxy (socks5 BND.ADDR), or the local address
+    /// of the connection to the proxy when the protocol has no such field
+    pub fn bound_addr(&self) -> Option<&TargetAddr> {
+        self.bound_addr.as_ref()
+    }
+
+    fn with_bound_addr(mut self, bound_addr: TargetAddr) -> Self {
+        self.bound_addr = Some(bound_addr);
+        self
+    }
+}
+
+impl fmt::Debug for ProxyStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProxyStream")
+            .field("bound_addr", &self.bound_addr)
+            .finish_non_exhaustive()
+    }
+}
+
+impl AsyncRead for ProxyStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ProxyStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+}
+
 #[derive(Debug, Clone, Default, PartialEq, Builder)]
 pub struct Proxy {
     #[builder(default)]
@@ -16,6 +119,10 @@ pub struct Proxy {
     port: u16,
     #[builder(default)]
     auth: ProxyAuth,
+    #[builder(default)]
+    tls: TlsOptions,
+    #[builder(default)]
+    dns_resolution: DnsResolution,
 }
 
 impl Proxy {
@@ -30,6 +137,8 @@ impl Proxy {
             host: host.into(),
             port: port.into(),
             auth,
+            tls: TlsOptions::default(),
+            dns_resolution: DnsResolution::default(),
         }
     }
 
@@ -37,23 +146,79 @@ impl Proxy {
         ProxyBuilder::default()
     }
 
+    /// Connects straight to the targets, without a proxy
+    pub fn direct() -> Self {
+        Self {
+            protocol: ProxyProtocol::Direct,
+            ..Self::default()
+        }
+    }
+
+    pub fn is_direct(&self) -> bool {
+        self.protocol == ProxyProtocol::Direct
+    }
+
+    /// Whether the target hostnames are resolved on this host instead of by the proxy
+    pub(crate) fn resolves_locally(&self) -> bool {
+        matches!(self.protocol, ProxyProtocol::Direct | ProxyProtocol::Socks4)
+            || self.dns_resolution == DnsResolution::Local
+    }
+
+    /// Whether the targets resolved on this host must be IPv4 addresses, since socks4 has no other address type
+    pub(crate) fn resolves_ipv4_only(&self) -> bool {
+        matches!(
+            self.protocol,
+            ProxyProtocol::Socks4 | ProxyProtocol::Socks4a
+        ) && self.resolves_locally()
+    }
+
     pub fn from_url(url: &str) -> Result<Self, ProxyError> {
         let parsed_url = Url::parse(url)?;
-        let protocol = match parsed_url.scheme() {
-            "http" | "https" => ProxyProtocol::Http,
-            "socks5" => ProxyProtocol::Socks5,
+
+        if parsed_url.scheme() == "direct" {
+            return Ok(Self::direct());
+        }
+
+        // Same as curl: "socks4" and "socks5" resolve hostnames locally, while "socks4a"
+        // and "socks5h" leave that to the proxy
+        let (protocol, dns_resolution) = match parsed_url.scheme() {
+            "http" => (ProxyProtocol::Http, DnsResolution::Remote),
+            "https" => (ProxyProtocol::Https, DnsResolution::Remote),
+            "socks4" => (ProxyProtocol::Socks4, DnsResolution::Local),
+            "socks4a" => (ProxyProtocol::Socks4a, DnsResolution::Remote),
+            "socks5" => (ProxyProtocol::Socks5, DnsResolution::Local),
+            "socks5h" => (ProxyProtocol::Socks5, DnsResolution::Remote),
             protocol => return Err(ProxyError::InvalidProtocol(protocol.to_string())),
         };
         let host = match parsed_url.host_str() {
             Some(host) => host.to_string(),
             None => return Err(ProxyError::InvalidHost),
         };
-        let port = parsed_url.port_or_known_default().unwrap_or(80);
+        // The url crate knows no default port of the socks schemes
+        let default_port = match protocol {
+            ProxyProtocol::Socks4 | ProxyProtocol::Socks4a | ProxyProtocol::Socks5 => 1080,
+            _ => 80,
+        };
+        let port = parsed_url.port_or_known_default().unwrap_or(default_port);
         let mut auth = ProxyAuth::None;
 
         match (parsed_url.username(), parsed_url.password()) {
             (username, Some(password)) if !username.is_empty() => {
-                auth = ProxyAuth::Basic(BasicAuth::new(username, password));
+                // Decoded, so the session placeholders (e.g. "{session}") survive the url parsing
+                auth = ProxyAuth::Basic(BasicAuth::new(
+                    percent_decode_str(username).decode_utf8_lossy(),
+                    percent_decode_str(password).decode_utf8_lossy(),
+                ));
+            }
+            // SOCKS4 has no passwords, the username is sent as the user-id
+            (username, None)
+                if !username.is_empty()
+                    && matches!(protocol, ProxyProtocol::Socks4 | ProxyProtocol::Socks4a) =>
+            {
+                auth = ProxyAuth::Basic(BasicAuth::new(
+                    percent_decode_str(username).decode_utf8_lossy(),
+                    "",
+                ));
             }
             _ => (),
         }
@@ -63,33 +228,111 @@ impl Proxy {
             host,
             port,
             auth,
+            tls: TlsOptions::default(),
+            dns_resolution,
         })
     }
 }
 
+impl fmt::Display for Proxy {
+    // Credentials are left out on purpose, since this is used for logging
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = match (&self.protocol, self.dns_resolution) {
+            (ProxyProtocol::Direct, _) => return f.write_str("direct://"),
+            (ProxyProtocol::Http, _) => "http",
+            (ProxyProtocol::Https, _) => "https",
+            (ProxyProtocol::Socks4, _) => "socks4",
+            (ProxyProtocol::Socks4a, _) => "socks4a",
+            (ProxyProtocol::Socks5, DnsResolution::Local) => "socks5",
+            (ProxyProtocol::Socks5, DnsResolution::Remote) => "socks5h",
+        };
+
+        write!(f, "{}://{}:{}", scheme, self.host, self.port)
+    }
+}
+
 impl Proxy {
     pub async fn connect(
         &self,
         target_host: &str,
         target_port: u16,
-    ) -> Result<TcpStream, ProxyError> {
-        let proxy_addr = format!("{}:{}", self.host, self.port.to_string());
+    ) -> Result<ProxyStream, ProxyError> {
+        if self.is_direct() {
+            return connect_direct(target_host, target_port).await;
+        }
+
+        let stream = self.dial_stream().await?;
+
+        self.connect_with_stream(stream, target_host, target_port)
+            .await
+    }
+
+    pub async fn connect_with_timeout(
+        &self,
+        target_host: &str,
+        target_port: u16,
+        timeout: Duration,
+    ) -> Result<ProxyStream, ProxyError> {
+        tokio::time::timeout(timeout, self.connect(target_host, target_port))
+            .await
+            .unwrap_or_else(|_| Err(ProxyError::ConnectionTimeout))
+    }
+
+    /// Opens a tunnel to the target through an already established connection to this proxy.
+    ///
+    /// A direct proxy returns the stream as is, since it adds nothing to an existing tunnel.
+    pub async fn connect_with_stream(
+        &self,
+        stream: ProxyStream,
+        target_host: &str,
+        target_port: u16,
+    ) -> Result<ProxyStream, ProxyError> {
+        let resolved_host;
+        let target_host = match (self.dns_resolution, &self.protocol) {
+            // SOCKS4 resolves hostnames on its own, since only IPv4 addresses can be sent there
+            (DnsResolution::Local, ProxyProtocol::Socks4 | ProxyProtocol::Socks4a) => target_host,
+            (DnsResolution::Local, _) if target_host.parse::<IpAddr>().is_err() => {
+                resolved_host = resolve_host(target_host, target_port)
+                    .await?
+                    .ip()
+                    .to_string();
+                resolved_host.as_str()
+            }
+            _ => target_host,
+        };
+
+        let stream: ProxyStream = match self.protocol {
+            ProxyProtocol::Direct => stream,
+            ProxyProtocol::Http | ProxyProtocol::Https => {
+                let mut stream = match self.protocol {
+                    ProxyProtocol::Https => {
+                        // CONNECT replies carry no address, so the one of the underlying stream is kept
+                        let bound_addr = stream.bound_addr.clone();
+                        let tls_stream = ProxyStream::new(
+                            tls::connect_tls(stream, &self.host, &self.tls).await?,
+                        );
 
-        let stream = match self.protocol {
-            ProxyProtocol::Http => {
-                let mut stream = match TcpStream::connect(proxy_addr).await {
-                    Ok(stream) => stream,
-                    Err(err) => return Err(HttpError::IoError(err).into()),
+                        match bound_addr {
+                            Some(bound_addr) => tls_stream.with_bound_addr(bound_addr),
+                            None => tls_stream,
+                        }
+                    }
+                    _ => stream,
+                };
+                // IPv6 literals must be enclosed in brackets inside of the CONNECT authority
+                let authority_host = match target_host.parse::<Ipv6Addr>() {
+                    Ok(_) => format!("[{}]", target_host),
+                    Err(_) => target_host.to_string(),
                 };
 
                 match &self.auth {
                     ProxyAuth::None => {
-                        http_connect_tokio(&mut stream, target_host, target_port).await?;
+                        http_connect_tokio(&mut stream, &authority_host, target_port).await?;
                     }
                     ProxyAuth::Basic(BasicAuth { username, password }) => {
                         http_connect_tokio_with_basic_auth(
                             &mut stream,
-                            target_host,
+                            &authority_host,
                             target_port,
                             username,
                             password,
@@ -100,50 +343,125 @@ impl Proxy {
 
                 stream
             }
-            ProxyProtocol::Socks5 => match &self.auth {
-                ProxyAuth::None => Socks5Stream::connect(
-                    proxy_addr,
-                    target_host.to_string(),
+            ProxyProtocol::Socks4 | ProxyProtocol::Socks4a => {
+                let mut stream = stream;
+                let user_id = match &self.auth {
+                    ProxyAuth::None => "",
+                    ProxyAuth::Basic(BasicAuth { username, .. }) => username.as_str(),
+                };
+
+                let bound_addr = socks4::socks4_connect(
+                    &mut stream,
+                    target_host,
                     target_port,
-                    Socks5Config::default(),
+                    user_id,
+                    self.protocol == ProxyProtocol::Socks4a
+                        && self.dns_resolution == DnsResolution::Remote,
                 )
-                .await?
-                .get_socket(),
-                ProxyAuth::Basic(BasicAuth { username, password }) => {
-                    Socks5Stream::connect_with_password(
-                        proxy_addr,
-                        target_host.to_string(),
-                        target_port,
-                        username.to_string(),
-                        password.to_string(),
-                        Socks5Config::default(),
-                    )
-                    .await?
-                    .get_socket()
+                .await?;
+
+                match bound_addr {
+                    Some(bound_addr) => stream.with_bound_addr(TargetAddr::Ip(bound_addr)),
+                    None => stream,
                 }
-            },
+            }
+            ProxyProtocol::Socks5 => {
+                let mut socks5_stream =
+                    Socks5Stream::use_stream(stream, self.socks5_auth(), Socks5Config::default())
+                        .await?;
+
+                let bound_addr = socks5_stream
+                    .request(
+                        Socks5Command::TCPConnect,
+                        socks5::target_addr(target_host, target_port),
+                    )
+                    .await?;
+
+                socks5_stream.get_socket().with_bound_addr(bound_addr)
+            }
         };
 
         Ok(stream)
     }
 
-    pub async fn connect_with_timeout(
-        &self,
-        target_host: &str,
-        target_port: u16,
-        timeout: Duration,
-    ) -> Result<TcpStream, ProxyError> {
-        tokio::time::timeout(timeout, self.connect(target_host, target_port))
+    fn socks5_auth(&self) -> Option<AuthenticationMethod> {
+        match &self.auth {
+            ProxyAuth::None => None,
+            ProxyAuth::Basic(BasicAuth { username, password }) => {
+                Some(AuthenticationMethod::Password {
+                    username: username.to_string(),
+                    password: password.to_string(),
+                })
+            }
+        }
+    }
+
+    async fn dial(&self) -> Result<TcpStream, ProxyError> {
+        let proxy_addr = format!("{}:{}", self.host, self.port.to_string());
+
+        TcpStream::connect(proxy_addr)
             .await
-            .unwrap_or_else(|_| Err(ProxyError::ConnectionTimeout))
+            .map_err(|err| match self.protocol {
+                ProxyProtocol::Http | ProxyProtocol::Https => {
+                    ProxyError::from(HttpError::IoError(err))
+                }
+                ProxyProtocol::Socks4 | ProxyProtocol::Socks4a => {
+                    ProxyError::from(Socks4Error::Io(err))
+                }
+                ProxyProtocol::Socks5 => ProxyError::from(SocksError::Io(err)),
+                ProxyProtocol::Direct => ProxyError::ConnectError(err),
+            })
     }
+
+    /// Dials the proxy, the local address is kept as the bound address until a hop reports its own
+    pub(crate) async fn dial_stream(&self) -> Result<ProxyStream, ProxyError> {
+        let stream = self.dial().await?;
+        let local_addr = stream.local_addr().map_err(ProxyError::LocalAddrError)?;
+
+        Ok(ProxyStream::new(stream).with_bound_addr(TargetAddr::Ip(local_addr)))
+    }
+}
+
+async fn connect_direct(target_host: &str, target_port: u16) -> Result<ProxyStream, ProxyError> {
+    let target_addr = match target_host.parse::<IpAddr>() {
+        Ok(ip) => SocketAddr::new(ip, target_port),
+        Err(_) => resolve_host(target_host, target_port).await?,
+    };
+    let stream = TcpStream::connect(target_addr)
+        .await
+        .map_err(ProxyError::ConnectError)?;
+    let local_addr = stream.local_addr().map_err(ProxyError::LocalAddrError)?;
+
+    Ok(ProxyStream::new(stream).with_bound_addr(TargetAddr::Ip(local_addr)))
+}
+
+pub async fn resolve_host(host: &str, port: u16) -> Result<SocketAddr, ProxyError> {
+    lookup_host((host, port))
+        .await
+        .map_err(ProxyError::ResolveError)?
+        .next()
+        .ok_or_else(|| ProxyError::UnresolvedHost(host.to_string()))
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum DnsResolution {
+    /// Target hostnames are passed to the upstream proxy unchanged (`socks5h://`)
+    #[default]
+    Remote,
+    /// Target hostnames are resolved on this host and only the IP is passed to the upstream proxy (`socks5://`)
+    Local,
 }
 
 #[derive(Debug, Clone, Default, PartialEq)]
 pub enum ProxyProtocol {
     #[default]
     Http,
+    Https,
+    Socks4,
+    Socks4a,
     Socks5,
+    /// No proxy, the target is connected directly
+    Direct,
 }
 
 #[derive(Debug, Clone, Default, PartialEq)]
@@ -182,11 +500,36 @@ pub enum ProxyError {
     InvalidProtocol(String),
     #[error("Invalid proxy host")]
     InvalidHost,
+    #[error("Proxy chain has no hops")]
+    EmptyChain,
+    #[error("No upstream proxy available")]
+    NoUpstream,
+    #[error("All upstream proxies are at their connection limit")]
+    UpstreamBusy,
+    #[error("Upstream doesn't support UDP: {0}")]
+    UdpNotSupported(String),
+    #[error("Upstream doesn't support BIND: {0}")]
+    BindNotSupported(String),
+
+    #[error("Host resolve error: {0}")]
+    ResolveError(io::Error),
+    #[error("Host can't be resolved: {0}")]
+    UnresolvedHost(String),
 
     #[error("Connection timeout")]
     ConnectionTimeout,
+    #[error("Can't connect to the target: {0}")]
+    ConnectError(io::Error),
+    #[error("Can't get the local address: {0}")]
+    LocalAddrError(io::Error),
+    #[error("Invalid tls config: {0}")]
+    InvalidTlsConfig(String),
+    #[error("Tls error: {0}")]
+    TlsError(io::Error),
     #[error("Http proxy error: {0}")]
     HttpError(#[from] HttpError),
+    #[error("Socks4 proxy error: {0}")]
+    Socks4Error(#[from] Socks4Error),
     #[error("Socks proxy error: {0}")]
     SocksError(#[from] SocksError),
 }
@@ -0,0 +1,144 @@
+use super::{Proxy, ProxyError, ProxyStream};
+use std::fmt;
+use std::time::Duration;
+
+/// A list of proxies where every hop is reached through the tunnel opened by the previous one
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProxyChain {
+    hops: Vec<Proxy>,
+}
+
+impl ProxyChain {
+    pub fn new(hops: impl Into<Vec<Proxy>>) -> Self {
+        Self { hops: hops.into() }
+    }
+
+    pub fn from_urls<I, S>(urls: I) -> Result<Self, ProxyError>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let hops = urls
+            .into_iter()
+            .map(|url| Proxy::from_url(url.as_ref()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self { hops })
+    }
+
+    pub fn hops(&self) -> &[Proxy] {
+        &self.hops
+    }
+
+    pub fn push(&mut self, proxy: Proxy) {
+        self.hops.push(proxy);
+    }
+
+    /// Whether the target hostnames are resolved on this host, by the last proxy or by a chain of direct hops
+    pub(crate) fn resolves_locally(&self) -> bool {
+        match self.hops.iter().rev().find(|hop| !hop.is_direct()) {
+            Some(last_hop) => last_hop.resolves_locally(),
+            None => true,
+        }
+    }
+
+    /// Whether the targets resolved on this host must be IPv4 addresses, as required by a socks4 last proxy
+    pub(crate) fn resolves_ipv4_only(&self) -> bool {
+        self.hops
+            .iter()
+            .rev()
+            .find(|hop| !hop.is_direct())
+            .is_some_and(Proxy::resolves_ipv4_only)
+    }
+}
+
+impl ProxyChain {
+    pub async fn connect(
+        &self,
+        target_host: &str,
+        target_port: u16,
+    ) -> Result<ProxyStream, ProxyError> {
+        // Direct hops add no tunnel, so a chain of direct hops only connects straight to the target
+        if let Some(first) = self
+            .hops
+            .first()
+            .filter(|_| self.hops.iter().all(Proxy::is_direct))
+        {
+            return first.connect(target_host, target_port).await;
+        }
+
+        let (stream, last_hop) = self.connect_last_hop().await?;
+
+        last_hop
+            .connect_with_stream(stream, target_host, target_port)
+            .await
+    }
+
+    pub async fn connect_with_timeout(
+        &self,
+        target_host: &str,
+        target_port: u16,
+        timeout: Duration,
+    ) -> Result<ProxyStream, ProxyError> {
+        tokio::time::timeout(timeout, self.connect(target_host, target_port))
+            .await
+            .unwrap_or_else(|_| Err(ProxyError::ConnectionTimeout))
+    }
+}
+
+impl ProxyChain {
+    /// Tunnels through all hops but the last one, returns the stream to the last hop.
+    ///
+    /// Direct hops are skipped.
+    pub(crate) async fn connect_last_hop(&self) -> Result<(ProxyStream, &Proxy), ProxyError> {
+        let mut hops = self.hops.iter().filter(|hop| !hop.is_direct());
+        let first = hops.next().ok_or(ProxyError::EmptyChain)?;
+        let mut stream = first.dial_stream().await?;
+        let mut current = first;
+
+        for next in hops {
+            let next_host = next.host.trim_start_matches('[').trim_end_matches(']');
+
+            stream = current
+                .connect_with_stream(stream, next_host, next.port)
+                .await?;
+            current = next;
+        }
+
+        Ok((stream, current))
+    }
+}
+
+impl fmt::Display for ProxyChain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, hop) in self.hops.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" -> ")?;
+            }
+
+            write!(f, "{}", hop)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl From<Proxy> for ProxyChain {
+    fn from(proxy: Proxy) -> Self {
+        Self { hops: vec![proxy] }
+    }
+}
+
+impl From<Vec<Proxy>> for ProxyChain {
+    fn from(hops: Vec<Proxy>) -> Self {
+        Self { hops }
+    }
+}
+
+impl FromIterator<Proxy> for ProxyChain {
+    fn from_iter<T: IntoIterator<Item = Proxy>>(iter: T) -> Self {
+        Self {
+            hops: iter.into_iter().collect(),
+        }
+    }
+}
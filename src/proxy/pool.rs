@@ -0,0 +1,745 @@
+use super::{Proxy, ProxyChain, ProxyError, SessionParams};
+use rand::Rng;
+use std::collections::hash_map::DefaultHasher;
+use std::fmt::Debug;
+use std::hash::{Hash, Hasher};
+use std::pin::pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+/// Picks the upstream for every new connection
+pub trait BalanceStrategy: Debug + Send + Sync {
+    /// Returns the position of the chosen upstream in `candidates`
+    fn select(&self, candidates: &[Candidate<'_>]) -> Option<usize>;
+}
+
+#[derive(Debug, Default)]
+pub struct RoundRobin {
+    next: AtomicUsize,
+}
+
+impl BalanceStrategy for RoundRobin {
+    fn select(&self, candidates: &[Candidate<'_>]) -> Option<usize> {
+        if candidates.is_empty() {
+            return None;
+        }
+
+        Some(self.next.fetch_add(1, Ordering::Relaxed) % candidates.len())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Random;
+
+impl BalanceStrategy for Random {
+    fn select(&self, candidates: &[Candidate<'_>]) -> Option<usize> {
+        if candidates.is_empty() {
+            return None;
+        }
+
+        Some(rand::thread_rng().gen_range(0..candidates.len()))
+    }
+}
+
+/// Random choice where the probability of an upstream is proportional to its weight
+#[derive(Debug, Default)]
+pub struct Weighted;
+
+impl BalanceStrategy for Weighted {
+    fn select(&self, candidates: &[Candidate<'_>]) -> Option<usize> {
+        let total: u64 = candidates.iter().map(|c| c.weight() as u64).sum();
+
+        if total == 0 {
+            return None;
+        }
+
+        let mut point = rand::thread_rng().gen_range(0..total);
+
+        candidates.iter().position(|c| {
+            if point < c.weight() as u64 {
+                return true;
+            }
+
+            point -= c.weight() as u64;
+            false
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct LeastConnections;
+
+impl BalanceStrategy for LeastConnections {
+    fn select(&self, candidates: &[Candidate<'_>]) -> Option<usize> {
+        candidates
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, c)| c.active_connections())
+            .map(|(position, _)| position)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct PoolMember {
+    upstream: ProxyChain,
+    weight: u32,
+    region: Option<String>,
+    /// Shared with the pools carrying the counters over, see `ProxyPool::carry_counters`
+    active: Arc<AtomicUsize>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Candidate<'a> {
+    index: usize,
+    member: &'a PoolMember,
+}
+
+impl Candidate<'_> {
+    /// Index of the upstream in the pool
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn upstream(&self) -> &ProxyChain {
+        &self.member.upstream
+    }
+
+    pub fn weight(&self) -> u32 {
+        self.member.weight
+    }
+
+    pub fn region(&self) -> Option<&str> {
+        self.member.region.as_deref()
+    }
+
+    pub fn active_connections(&self) -> usize {
+        self.member.active.load(Ordering::Relaxed)
+    }
+}
+
+/// A set of upstreams sharing the connections by the balance strategy.
+///
+/// Clones share the same upstreams, strategy state and active connection counters.
+#[derive(Debug, Clone)]
+pub struct ProxyPool {
+    members: Arc<[PoolMember]>,
+    strategy: Arc<dyn BalanceStrategy>,
+    limits: PoolLimits,
+    queue: Arc<PoolQueue>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct PoolLimits {
+    max_connections: Option<usize>,
+    max_queued: usize,
+}
+
+/// Requests waiting for an upstream below its connection limit
+#[derive(Debug, Default)]
+struct PoolQueue {
+    released: Notify,
+    waiting: AtomicUsize,
+}
+
+enum Selection {
+    Leased(PoolLease),
+    /// All of the candidates are at their connection limit
+    Busy,
+    Empty,
+}
+
+impl ProxyPool {
+    pub fn new<I, P>(upstreams: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<ProxyChain>,
+    {
+        upstreams
+            .into_iter()
+            .fold(Self::builder(), |builder, upstream| {
+                builder.upstream(upstream)
+            })
+            .build()
+    }
+
+    pub fn builder() -> ProxyPoolBuilder {
+        ProxyPoolBuilder::default()
+    }
+
+    pub fn from_urls<I, S>(urls: I) -> Result<Self, ProxyError>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let upstreams = urls
+            .into_iter()
+            .map(|url| Proxy::from_url(url.as_ref()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self::new(upstreams))
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    pub fn upstreams(&self) -> impl Iterator<Item = &ProxyChain> {
+        self.members.iter().map(|member| &member.upstream)
+    }
+
+    pub fn select(&self) -> Option<PoolLease> {
+        self.select_excluding(&[])
+    }
+
+    /// Same as `select`, but upstreams with the given indexes are not considered
+    pub fn select_excluding(&self, excluded: &[usize]) -> Option<PoolLease> {
+        self.select_for_session(&SessionParams::default(), excluded)
+    }
+
+    /// Prefers the upstreams of the session region and keeps sticky sessions on the same
+    /// upstream, otherwise the balance strategy decides.
+    ///
+    /// Upstreams at their connection limit are skipped, sticky sessions get `None` if their upstream is.
+    pub fn select_for_session(
+        &self,
+        params: &SessionParams,
+        excluded: &[usize],
+    ) -> Option<PoolLease> {
+        match self.try_select(params, excluded) {
+            Selection::Leased(lease) => Some(lease),
+            Selection::Busy | Selection::Empty => None,
+        }
+    }
+
+    /// Same as `select_for_session`, but waits up to `timeout` in the queue of the pool
+    /// when all of the upstreams are at their connection limit
+    pub async fn acquire_for_session(
+        &self,
+        params: &SessionParams,
+        excluded: &[usize],
+        timeout: Duration,
+    ) -> Result<PoolLease, ProxyError> {
+        let deadline = Instant::now() + timeout;
+        let mut queued: Option<QueueSlot> = None;
+
+        loop {
+            // Registered before the selection, so a release in between isn't missed
+            let mut released = pin!(self.queue.released.notified());
+
+            released.as_mut().enable();
+
+            match self.try_select(params, excluded) {
+                Selection::Leased(lease) => return Ok(lease),
+                Selection::Empty => return Err(ProxyError::NoUpstream),
+                Selection::Busy => (),
+            }
+
+            if queued.is_none() {
+                queued = Some(
+                    QueueSlot::enter(self.queue.clone(), self.limits.max_queued)
+                        .ok_or(ProxyError::UpstreamBusy)?,
+                );
+            }
+
+            if tokio::time::timeout_at(deadline, released).await.is_err() {
+                return Err(ProxyError::UpstreamBusy);
+            }
+        }
+    }
+
+    /// Shares the active connection counters of the upstreams also found in `previous`, so the connections
+    /// of the replaced pool still count towards the limits
+    pub(crate) fn carry_counters(&mut self, previous: &ProxyPool) {
+        self.members = self
+            .members
+            .iter()
+            .map(|member| {
+                let active = previous
+                    .members
+                    .iter()
+                    .find(|previous_member| previous_member.upstream == member.upstream)
+                    .map_or_else(
+                        || member.active.clone(),
+                        |previous_member| previous_member.active.clone(),
+                    );
+
+                PoolMember {
+                    active,
+                    ..member.clone()
+                }
+            })
+            .collect();
+        // The released leases of the previous pool wake up the requests queued here
+        self.queue = previous.queue.clone();
+    }
+
+    fn try_select(&self, params: &SessionParams, excluded: &[usize]) -> Selection {
+        let mut candidates: Vec<Candidate<'_>> = self
+            .members
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| !excluded.contains(index))
+            .map(|(index, member)| Candidate { index, member })
+            .collect();
+
+        if let Some(region) = params.region() {
+            // Upstreams of other regions are used only if none is tagged with the requested one
+            if candidates.iter().any(|c| c.region() == Some(region)) {
+                candidates.retain(|c| c.region() == Some(region));
+            }
+        }
+
+        if candidates.is_empty() {
+            return Selection::Empty;
+        }
+
+        let max_connections = self.limits.max_connections;
+        let index = match params.sticky_key() {
+            Some(key) => {
+                let mut hasher = DefaultHasher::new();
+                key.hash(&mut hasher);
+
+                // The sticky upstream is kept even if it's full, so the session waits for it
+                candidates[(hasher.finish() % candidates.len() as u64) as usize].index
+            }
+            None => {
+                candidates
+                    .retain(|c| !max_connections.is_some_and(|max| c.active_connections() >= max));
+
+                if candidates.is_empty() {
+                    return Selection::Busy;
+                }
+
+                match self
+                    .strategy
+                    .select(&candidates)
+                    .and_then(|position| candidates.get(position))
+                {
+                    Some(candidate) => candidate.index,
+                    None => return Selection::Empty,
+                }
+            }
+        };
+
+        match PoolLease::acquire(
+            self.members.clone(),
+            self.queue.clone(),
+            index,
+            max_connections,
+        ) {
+            Some(lease) => Selection::Leased(lease),
+            None => Selection::Busy,
+        }
+    }
+}
+
+impl Default for ProxyPool {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+impl PartialEq for ProxyPool {
+    fn eq(&self, other: &Self) -> bool {
+        let same_strategy = std::ptr::eq(
+            Arc::as_ptr(&self.strategy) as *const (),
+            Arc::as_ptr(&other.strategy) as *const (),
+        );
+
+        same_strategy
+            && self.limits == other.limits
+            && self.members.len() == other.members.len()
+            && self.members.iter().zip(other.members.iter()).all(|(a, b)| {
+                a.upstream == b.upstream && a.weight == b.weight && a.region == b.region
+            })
+    }
+}
+
+impl From<Proxy> for ProxyPool {
+    fn from(proxy: Proxy) -> Self {
+        Self::new([proxy])
+    }
+}
+
+impl From<ProxyChain> for ProxyPool {
+    fn from(chain: ProxyChain) -> Self {
+        Self::new([chain])
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ProxyPoolBuilder {
+    members: Vec<PoolMember>,
+    strategy: Option<Arc<dyn BalanceStrategy>>,
+    limits: PoolLimits,
+}
+
+impl ProxyPoolBuilder {
+    pub fn upstream(self, upstream: impl Into<ProxyChain>) -> Self {
+        self.weighted_upstream(upstream, 1)
+    }
+
+    pub fn weighted_upstream(mut self, upstream: impl Into<ProxyChain>, weight: u32) -> Self {
+        self.members.push(PoolMember {
+            upstream: upstream.into(),
+            weight,
+            region: None,
+            active: Arc::default(),
+        });
+        self
+    }
+
+    /// Adds an upstream tagged with a region, which is preferred by the sessions of that region
+    pub fn regional_upstream(
+        mut self,
+        upstream: impl Into<ProxyChain>,
+        region: impl Into<String>,
+    ) -> Self {
+        self.members.push(PoolMember {
+            upstream: upstream.into(),
+            weight: 1,
+            region: Some(region.into()),
+            active: Arc::default(),
+        });
+        self
+    }
+
+    pub fn strategy(mut self, strategy: impl BalanceStrategy + 'static) -> Self {
+        self.strategy = Some(Arc::new(strategy));
+        self
+    }
+
+    /// Limits the active connections of every upstream, e.g. to the cap of the proxy provider account.
+    ///
+    /// New connections fail over to the upstreams below the limit.
+    pub fn max_connections_per_upstream(mut self, max_connections: usize) -> Self {
+        self.limits.max_connections = Some(max_connections);
+        self
+    }
+
+    /// How many requests may wait for a free upstream when all of them are at their limit,
+    /// the requests over it fail immediately (0 by default)
+    pub fn max_queued(mut self, max_queued: usize) -> Self {
+        self.limits.max_queued = max_queued;
+        self
+    }
+
+    pub fn build(self) -> ProxyPool {
+        ProxyPool {
+            members: self.members.into(),
+            strategy: self
+                .strategy
+                .unwrap_or_else(|| Arc::new(RoundRobin::default())),
+            limits: self.limits,
+            queue: Arc::default(),
+        }
+    }
+}
+
+/// An upstream chosen from the pool, counted as an active connection until dropped
+#[derive(Debug)]
+pub struct PoolLease {
+    members: Arc<[PoolMember]>,
+    queue: Arc<PoolQueue>,
+    index: usize,
+}
+
+impl PoolLease {
+    /// Counts the connection unless the upstream is at the limit
+    fn acquire(
+        members: Arc<[PoolMember]>,
+        queue: Arc<PoolQueue>,
+        index: usize,
+        max_connections: Option<usize>,
+    ) -> Option<Self> {
+        members[index]
+            .active
+            .fetch_update(
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+                |active| match max_connections {
+                    Some(max) if active >= max => None,
+                    _ => Some(active + 1),
+                },
+            )
+            .ok()?;
+
+        Some(Self {
+            members,
+            queue,
+            index,
+        })
+    }
+
+    /// Index of the upstream in the pool
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn upstream(&self) -> &ProxyChain {
+        &self.members[self.index].upstream
+    }
+}
+
+impl Drop for PoolLease {
+    fn drop(&mut self) {
+        self.members[self.index]
+            .active
+            .fetch_sub(1, Ordering::Relaxed);
+        self.queue.released.notify_waiters();
+    }
+}
+
+/// A place in the queue of the pool, left when dropped
+struct QueueSlot {
+    queue: Arc<PoolQueue>,
+}
+
+impl QueueSlot {
+    fn enter(queue: Arc<PoolQueue>, max_queued: usize) -> Option<Self> {
+        queue
+            .waiting
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |waiting| {
+                (waiting < max_queued).then_some(waiting + 1)
+            })
+            .ok()?;
+
+        Some(Self { queue })
+    }
+}
+
+impl Drop for QueueSlot {
+    fn drop(&mut self) {
+        self.queue.waiting.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upstream(port: u16) -> Proxy {
+        Proxy::from_url(&format!("socks5h://192.0.2.1:{}", port)).unwrap()
+    }
+
+    /// Pool of single connection upstreams
+    fn limited_pool(upstreams: usize, max_queued: usize) -> ProxyPool {
+        (0..upstreams)
+            .fold(ProxyPool::builder(), |builder, port| {
+                builder.upstream(upstream(1080 + port as u16))
+            })
+            .max_connections_per_upstream(1)
+            .max_queued(max_queued)
+            .build()
+    }
+
+    fn sticky_session() -> SessionParams {
+        SessionParams::default().with_session("abc")
+    }
+
+    #[test]
+    fn round_robin_cycles_the_upstreams() {
+        let pool = ProxyPool::new([upstream(1080), upstream(1081), upstream(1082)]);
+        let indexes: Vec<usize> = (0..6).map(|_| pool.select().unwrap().index()).collect();
+
+        assert_eq!(indexes, [0, 1, 2, 0, 1, 2]);
+        assert_eq!(pool.select_excluding(&[0, 1]).unwrap().index(), 2);
+        assert!(pool.select_excluding(&[0, 1, 2]).is_none());
+    }
+
+    #[test]
+    fn weighted_skips_zero_weights() {
+        let pool = ProxyPool::builder()
+            .weighted_upstream(upstream(1080), 0)
+            .weighted_upstream(upstream(1081), 3)
+            .weighted_upstream(upstream(1082), 0)
+            .strategy(Weighted)
+            .build();
+
+        for _ in 0..100 {
+            assert_eq!(pool.select().unwrap().index(), 1);
+        }
+
+        let pool = ProxyPool::builder()
+            .weighted_upstream(upstream(1080), 0)
+            .weighted_upstream(upstream(1081), 0)
+            .strategy(Weighted)
+            .build();
+
+        assert!(pool.select().is_none());
+    }
+
+    #[test]
+    fn least_connections_prefers_idle_upstreams() {
+        let pool = ProxyPool::builder()
+            .upstream(upstream(1080))
+            .upstream(upstream(1081))
+            .strategy(LeastConnections)
+            .build();
+        let first = pool.select().unwrap();
+        let second = pool.select().unwrap();
+
+        assert_eq!(first.index(), 0);
+        assert_eq!(second.index(), 1);
+
+        drop(first);
+
+        assert_eq!(pool.select().unwrap().index(), 0);
+    }
+
+    #[test]
+    fn lease_drop_releases_the_upstream() {
+        let pool = limited_pool(1, 0);
+        let lease = pool.select().unwrap();
+
+        assert!(pool.select().is_none());
+
+        drop(lease);
+
+        assert!(pool.select().is_some());
+    }
+
+    #[test]
+    fn full_upstreams_are_skipped() {
+        let pool = limited_pool(2, 0);
+        let first = pool.select().unwrap();
+        let second = pool.select().unwrap();
+
+        assert_ne!(first.index(), second.index());
+        assert!(pool.select().is_none());
+    }
+
+    #[tokio::test]
+    async fn acquire_waits_for_a_released_upstream() {
+        let pool = limited_pool(1, 1);
+        let lease = pool.select().unwrap();
+        let release = async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(lease);
+        };
+        let (acquired, _) = tokio::join!(
+            pool.acquire_for_session(&SessionParams::default(), &[], Duration::from_secs(5)),
+            release
+        );
+
+        assert_eq!(acquired.unwrap().index(), 0);
+    }
+
+    #[tokio::test]
+    async fn acquire_fails_after_the_deadline() {
+        let pool = limited_pool(1, 1);
+        let _lease = pool.select().unwrap();
+        let started_at = Instant::now();
+        let acquired = pool
+            .acquire_for_session(&SessionParams::default(), &[], Duration::from_millis(50))
+            .await;
+
+        assert!(matches!(acquired, Err(ProxyError::UpstreamBusy)));
+        assert!(started_at.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn acquire_fails_when_the_queue_is_full() {
+        let pool = limited_pool(1, 1);
+        let lease = pool.select().unwrap();
+        let queued_pool = pool.clone();
+        let queued = tokio::spawn(async move {
+            queued_pool
+                .acquire_for_session(&SessionParams::default(), &[], Duration::from_secs(5))
+                .await
+                .map(|lease| lease.index())
+        });
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        let started_at = Instant::now();
+        let overflowed = pool
+            .acquire_for_session(&SessionParams::default(), &[], Duration::from_secs(5))
+            .await;
+
+        assert!(matches!(overflowed, Err(ProxyError::UpstreamBusy)));
+        assert!(started_at.elapsed() < Duration::from_secs(1));
+
+        drop(lease);
+
+        assert_eq!(queued.await.unwrap().unwrap(), 0);
+
+        // Without a queue the requests fail right away
+        let pool = limited_pool(1, 0);
+        let _lease = pool.select().unwrap();
+
+        assert!(matches!(
+            pool.acquire_for_session(&SessionParams::default(), &[], Duration::from_secs(5))
+                .await,
+            Err(ProxyError::UpstreamBusy)
+        ));
+    }
+
+    #[tokio::test]
+    async fn sticky_sessions_wait_for_their_upstream() {
+        let pool = limited_pool(2, 1);
+        let lease = pool
+            .acquire_for_session(&sticky_session(), &[], Duration::from_secs(5))
+            .await
+            .unwrap();
+        let sticky_index = lease.index();
+
+        // The other upstream is free, but the session stays on its own
+        assert!(pool.select_for_session(&sticky_session(), &[]).is_none());
+        assert!(matches!(
+            pool.acquire_for_session(&sticky_session(), &[], Duration::from_millis(20))
+                .await,
+            Err(ProxyError::UpstreamBusy)
+        ));
+
+        let release = async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(lease);
+        };
+        let (acquired, _) = tokio::join!(
+            pool.acquire_for_session(&sticky_session(), &[], Duration::from_secs(5)),
+            release
+        );
+
+        assert_eq!(acquired.unwrap().index(), sticky_index);
+    }
+
+    #[tokio::test]
+    async fn carried_counters_are_shared() {
+        let previous = limited_pool(2, 0);
+        let lease = previous.select_excluding(&[1]).unwrap();
+        // The same upstreams in another order, with one more queued request allowed
+        let mut pool = ProxyPool::builder()
+            .upstream(upstream(1081))
+            .upstream(upstream(1080))
+            .upstream(upstream(1082))
+            .max_connections_per_upstream(1)
+            .max_queued(1)
+            .build();
+
+        pool.carry_counters(&previous);
+
+        // The upstream leased from the previous pool is at its limit in the new one as well
+        assert!(pool.select_excluding(&[0, 2]).is_none());
+        assert!(pool.select_excluding(&[1, 2]).is_some());
+
+        // Releasing the previous lease wakes up the requests queued on the new pool
+        let release = async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(lease);
+        };
+        let (acquired, _) = tokio::join!(
+            pool.acquire_for_session(&SessionParams::default(), &[0, 2], Duration::from_secs(5)),
+            release
+        );
+
+        assert_eq!(acquired.unwrap().index(), 1);
+    }
+}
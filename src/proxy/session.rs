@@ -0,0 +1,93 @@
+use super::{BasicAuth, Proxy, ProxyAuth, ProxyChain};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    /// Every connection may go through a different upstream
+    PerConnection,
+    /// Connections of the same session stay on the same upstream
+    Sticky,
+}
+
+/// Routing parameters of a client session, usually encoded in the client username
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SessionParams {
+    session: Option<String>,
+    region: Option<String>,
+    rotation: Option<Rotation>,
+}
+
+impl SessionParams {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_session(mut self, session: impl Into<String>) -> Self {
+        self.session = Some(session.into());
+        self
+    }
+
+    pub fn with_region(mut self, region: impl Into<String>) -> Self {
+        self.region = Some(region.into());
+        self
+    }
+
+    pub fn with_rotation(mut self, rotation: Rotation) -> Self {
+        self.rotation = Some(rotation);
+        self
+    }
+
+    pub fn session(&self) -> Option<&str> {
+        self.session.as_deref()
+    }
+
+    pub fn region(&self) -> Option<&str> {
+        self.region.as_deref()
+    }
+
+    pub fn rotation(&self) -> Option<Rotation> {
+        self.rotation
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.session.is_none() && self.region.is_none() && self.rotation.is_none()
+    }
+
+    /// The key which must always lead to the same upstream, sessions are sticky unless
+    /// per-connection rotation was requested
+    pub fn sticky_key(&self) -> Option<&str> {
+        match self.rotation {
+            Some(Rotation::PerConnection) => None,
+            _ => self.session.as_deref(),
+        }
+    }
+
+    /// Replaces `{session}` and `{region}` placeholders, missing values are rendered as empty strings
+    pub fn render(&self, template: &str) -> String {
+        template
+            .replace("{session}", self.session.as_deref().unwrap_or_default())
+            .replace("{region}", self.region.as_deref().unwrap_or_default())
+    }
+}
+
+impl Proxy {
+    /// Returns the proxy with the session placeholders rendered in its username
+    pub fn with_session(&self, params: &SessionParams) -> Proxy {
+        let mut proxy = self.clone();
+
+        if let ProxyAuth::Basic(BasicAuth { username, .. }) = &mut proxy.auth {
+            *username = params.render(username);
+        }
+
+        proxy
+    }
+}
+
+impl ProxyChain {
+    /// Returns the chain with the session placeholders rendered in the usernames of all hops
+    pub fn with_session(&self, params: &SessionParams) -> ProxyChain {
+        self.hops()
+            .iter()
+            .map(|hop| hop.with_session(params))
+            .collect()
+    }
+}
@@ -0,0 +1,112 @@
+use super::ProxyError;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::lookup_host;
+
+const SOCKS4_VERSION: u8 = 4;
+const SOCKS4_REPLY_VERSION: u8 = 0;
+const SOCKS4_CMD_CONNECT: u8 = 1;
+
+const SOCKS4_REPLY_GRANTED: u8 = 90;
+const SOCKS4_REPLY_REJECTED: u8 = 91;
+const SOCKS4_REPLY_IDENTD_UNREACHABLE: u8 = 92;
+const SOCKS4_REPLY_IDENTD_MISMATCH: u8 = 93;
+
+pub(crate) async fn socks4_connect<S>(
+    stream: &mut S,
+    target_host: &str,
+    target_port: u16,
+    user_id: &str,
+    remote_resolution: bool,
+) -> Result<Option<SocketAddr>, ProxyError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut request = vec![SOCKS4_VERSION, SOCKS4_CMD_CONNECT];
+    request.extend_from_slice(&target_port.to_be_bytes());
+
+    match target_host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.extend_from_slice(&ip.octets());
+            request.extend_from_slice(user_id.as_bytes());
+            request.push(0);
+        }
+        Ok(IpAddr::V6(_)) => return Err(Socks4Error::UnsupportedAddress.into()),
+        Err(_) if remote_resolution => {
+            // SOCKS4a: an invalid IP 0.0.0.x tells the proxy to resolve the hostname
+            // that follows the user-id
+            request.extend_from_slice(&[0, 0, 0, 1]);
+            request.extend_from_slice(user_id.as_bytes());
+            request.push(0);
+            request.extend_from_slice(target_host.as_bytes());
+            request.push(0);
+        }
+        Err(_) => {
+            let ip = resolve_ipv4(target_host, target_port).await?;
+
+            request.extend_from_slice(&ip.octets());
+            request.extend_from_slice(user_id.as_bytes());
+            request.push(0);
+        }
+    }
+
+    stream.write_all(&request).await.map_err(Socks4Error::Io)?;
+    stream.flush().await.map_err(Socks4Error::Io)?;
+
+    let mut reply = [0u8; 8];
+    stream
+        .read_exact(&mut reply)
+        .await
+        .map_err(Socks4Error::Io)?;
+
+    if reply[0] != SOCKS4_REPLY_VERSION {
+        return Err(Socks4Error::InvalidReplyVersion(reply[0]).into());
+    }
+
+    match reply[1] {
+        SOCKS4_REPLY_GRANTED => {
+            let port = u16::from_be_bytes([reply[2], reply[3]]);
+            let ip = Ipv4Addr::new(reply[4], reply[5], reply[6], reply[7]);
+
+            // Most of the proxies leave the address empty, since it's ignored for CONNECT
+            match ip.is_unspecified() {
+                true => Ok(None),
+                false => Ok(Some(SocketAddrV4::new(ip, port).into())),
+            }
+        }
+        SOCKS4_REPLY_REJECTED => Err(Socks4Error::RequestRejected.into()),
+        SOCKS4_REPLY_IDENTD_UNREACHABLE => Err(Socks4Error::IdentdUnreachable.into()),
+        SOCKS4_REPLY_IDENTD_MISMATCH => Err(Socks4Error::IdentdMismatch.into()),
+        code => Err(Socks4Error::UnknownReply(code).into()),
+    }
+}
+
+async fn resolve_ipv4(host: &str, port: u16) -> Result<Ipv4Addr, ProxyError> {
+    lookup_host((host, port))
+        .await
+        .map_err(ProxyError::ResolveError)?
+        .find_map(|addr| match addr {
+            SocketAddr::V4(addr) => Some(*addr.ip()),
+            SocketAddr::V6(_) => None,
+        })
+        .ok_or_else(|| ProxyError::UnresolvedHost(host.to_string()))
+}
+
+#[derive(Debug, Error)]
+pub enum Socks4Error {
+    #[error("Io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Only IPv4 target addresses are supported")]
+    UnsupportedAddress,
+    #[error("Invalid reply version: {0}")]
+    InvalidReplyVersion(u8),
+    #[error("Request rejected or failed")]
+    RequestRejected,
+    #[error("Request rejected because the proxy can't connect to identd on the client")]
+    IdentdUnreachable,
+    #[error("Request rejected because identd reported a different user-id")]
+    IdentdMismatch,
+    #[error("Unknown reply code: {0}")]
+    UnknownReply(u8),
+}
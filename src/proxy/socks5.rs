@@ -0,0 +1,346 @@
+use super::{
+    resolve_host, DnsResolution, Proxy, ProxyChain, ProxyError, ProxyProtocol, ProxyStream,
+};
+use anyhow::anyhow;
+use fast_socks5::client::{Config as Socks5Config, Socks5Stream};
+use fast_socks5::util::target_addr::TargetAddr;
+use fast_socks5::{ReplyError, Socks5Command, SocksError};
+use std::fmt;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::{TcpStream, UdpSocket};
+
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+/// Largest payload of a UDP datagram
+pub(crate) const MAX_DATAGRAM_SIZE: usize = 65_535;
+
+pub(crate) fn target_addr(host: &str, port: u16) -> TargetAddr {
+    match host.parse::<IpAddr>() {
+        Ok(ip) => TargetAddr::Ip(SocketAddr::new(ip, port)),
+        Err(_) => TargetAddr::Domain(host.to_string(), port),
+    }
+}
+
+/// Writes ATYP, ADDR and PORT fields of a socks5 message
+pub(crate) fn encode_addr(addr: &TargetAddr, buf: &mut Vec<u8>) {
+    match addr {
+        TargetAddr::Ip(SocketAddr::V4(addr)) => {
+            buf.push(ATYP_IPV4);
+            buf.extend_from_slice(&addr.ip().octets());
+            buf.extend_from_slice(&addr.port().to_be_bytes());
+        }
+        TargetAddr::Ip(SocketAddr::V6(addr)) => {
+            buf.push(ATYP_IPV6);
+            buf.extend_from_slice(&addr.ip().octets());
+            buf.extend_from_slice(&addr.port().to_be_bytes());
+        }
+        TargetAddr::Domain(domain, port) => {
+            // Domains are length-prefixed with a single byte
+            let domain = &domain.as_bytes()[..domain.len().min(u8::MAX as usize)];
+
+            buf.push(ATYP_DOMAIN);
+            buf.push(domain.len() as u8);
+            buf.extend_from_slice(domain);
+            buf.extend_from_slice(&port.to_be_bytes());
+        }
+    }
+}
+
+/// Reads ATYP, ADDR and PORT fields of a socks5 message, returns the address and the read length
+pub(crate) fn decode_addr(buf: &[u8]) -> Option<(TargetAddr, usize)> {
+    let (addr, len) = match *buf.first()? {
+        ATYP_IPV4 => {
+            let octets: [u8; 4] = buf.get(1..5)?.try_into().ok()?;
+
+            (
+                TargetAddr::Ip(SocketAddr::new(Ipv4Addr::from(octets).into(), 0)),
+                5,
+            )
+        }
+        ATYP_IPV6 => {
+            let octets: [u8; 16] = buf.get(1..17)?.try_into().ok()?;
+
+            (
+                TargetAddr::Ip(SocketAddr::new(Ipv6Addr::from(octets).into(), 0)),
+                17,
+            )
+        }
+        ATYP_DOMAIN => {
+            let domain_len = *buf.get(1)? as usize;
+            let domain = std::str::from_utf8(buf.get(2..2 + domain_len)?).ok()?;
+
+            (TargetAddr::Domain(domain.to_string(), 0), 2 + domain_len)
+        }
+        _ => return None,
+    };
+    let port = u16::from_be_bytes(buf.get(len..len + 2)?.try_into().ok()?);
+    let addr = match addr {
+        TargetAddr::Ip(addr) => TargetAddr::Ip(SocketAddr::new(addr.ip(), port)),
+        TargetAddr::Domain(domain, _) => TargetAddr::Domain(domain, port),
+    };
+
+    Some((addr, len + 2))
+}
+
+/// Reads a socks5 reply (VER, REP, RSV, ATYP, ADDR, PORT) and returns its address
+pub(crate) async fn read_reply<S>(stream: &mut S) -> Result<TargetAddr, ProxyError>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut header = [0u8; 5];
+    stream
+        .read_exact(&mut header)
+        .await
+        .map_err(SocksError::Io)?;
+
+    if header[0] != 5 {
+        return Err(SocksError::from(anyhow!("Unsupported reply version {}", header[0])).into());
+    }
+
+    if header[1] != 0 {
+        return Err(SocksError::ReplyError(reply_error_from_code(header[1])).into());
+    }
+
+    // The last header byte is either the first byte of the IP or the length of the domain,
+    // the port takes 2 more bytes
+    let remaining = match header[3] {
+        ATYP_IPV4 => 4 - 1 + 2,
+        ATYP_IPV6 => 16 - 1 + 2,
+        ATYP_DOMAIN => header[4] as usize + 2,
+        atyp => return Err(SocksError::from(anyhow!("Unknown address type {}", atyp)).into()),
+    };
+    let mut buf = vec![0u8; 5 + remaining];
+    buf[..5].copy_from_slice(&header);
+    stream
+        .read_exact(&mut buf[5..])
+        .await
+        .map_err(SocksError::Io)?;
+
+    let (addr, _) = decode_addr(&buf[3..])
+        .ok_or(SocksError::ReplyError(ReplyError::AddressTypeNotSupported))?;
+
+    Ok(addr)
+}
+
+fn reply_error_from_code(code: u8) -> ReplyError {
+    match code {
+        2 => ReplyError::ConnectionNotAllowed,
+        3 => ReplyError::NetworkUnreachable,
+        4 => ReplyError::HostUnreachable,
+        5 => ReplyError::ConnectionRefused,
+        6 => ReplyError::TtlExpired,
+        7 => ReplyError::CommandNotSupported,
+        8 => ReplyError::AddressTypeNotSupported,
+        _ => ReplyError::GeneralFailure,
+    }
+}
+
+/// Wraps the payload into the socks5 UDP request header (RSV, FRAG, ATYP, ADDR, PORT)
+pub(crate) fn encode_udp_datagram(addr: &TargetAddr, payload: &[u8]) -> Vec<u8> {
+    let mut datagram = Vec::with_capacity(payload.len() + 22);
+
+    datagram.extend_from_slice(&[0, 0, 0]);
+    encode_addr(addr, &mut datagram);
+    datagram.extend_from_slice(payload);
+
+    datagram
+}
+
+/// Splits a socks5 UDP datagram into the fragment number, the address and the payload
+pub(crate) fn decode_udp_datagram(datagram: &[u8]) -> Option<(u8, TargetAddr, &[u8])> {
+    let frag = *datagram.get(2)?;
+    let (addr, addr_len) = decode_addr(datagram.get(3..)?)?;
+
+    Some((frag, addr, &datagram[3 + addr_len..]))
+}
+
+/// A UDP association on the upstream socks5 proxy, it lives as long as its control connection
+#[derive(Debug)]
+pub struct UdpAssociation {
+    control: TcpStream,
+    socket: UdpSocket,
+    dns_resolution: DnsResolution,
+}
+
+impl UdpAssociation {
+    /// Whether the target hostnames are resolved on this host instead of by the proxy
+    pub(crate) fn resolves_locally(&self) -> bool {
+        self.dns_resolution == DnsResolution::Local
+    }
+
+    pub async fn send_to(&self, addr: &TargetAddr, payload: &[u8]) -> Result<(), ProxyError> {
+        let resolved_addr;
+        let addr = match (self.dns_resolution, addr) {
+            (DnsResolution::Local, TargetAddr::Domain(domain, port)) => {
+                resolved_addr = TargetAddr::Ip(resolve_host(domain, *port).await?);
+                &resolved_addr
+            }
+            _ => addr,
+        };
+
+        self.socket
+            .send(&encode_udp_datagram(addr, payload))
+            .await
+            .map_err(SocksError::Io)?;
+
+        Ok(())
+    }
+
+    /// Receives the next datagram relayed by the proxy into `buf`, returns its source address and payload.
+    ///
+    /// Datagrams longer than `buf` are truncated, a buffer of 65535 bytes fits any of them.
+    pub async fn recv_from<'a>(
+        &self,
+        buf: &'a mut [u8],
+    ) -> Result<(TargetAddr, &'a [u8]), ProxyError> {
+        loop {
+            let len = self.socket.recv(buf).await.map_err(SocksError::Io)?;
+
+            // Fragmented datagrams are not supported, so they are dropped
+            if let Some((0, addr, payload)) = decode_udp_datagram(&buf[..len]) {
+                let payload_start = len - payload.len();
+
+                return Ok((addr, &buf[payload_start..len]));
+            }
+        }
+    }
+
+    /// Resolves when the proxy closes the control connection, which ends the association
+    pub async fn closed(&self) {
+        let mut buf = [0u8; 64];
+
+        loop {
+            if self.control.readable().await.is_err() {
+                return;
+            }
+
+            match self.control.try_read(&mut buf) {
+                Ok(0) => return,
+                Ok(_) => continue,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+/// A BIND request accepted by the upstream socks5 proxy, which waits for the incoming connection
+pub struct PendingBind {
+    stream: ProxyStream,
+    bound_addr: TargetAddr,
+}
+
+impl PendingBind {
+    /// The address where the proxy listens for the incoming connection
+    pub fn bound_addr(&self) -> &TargetAddr {
+        &self.bound_addr
+    }
+
+    /// Waits for the incoming connection, returns its peer address and the stream
+    pub async fn accept(mut self) -> Result<(TargetAddr, ProxyStream), ProxyError> {
+        let peer_addr = read_reply(&mut self.stream).await?;
+
+        Ok((peer_addr, self.stream))
+    }
+}
+
+impl fmt::Debug for PendingBind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PendingBind")
+            .field("bound_addr", &self.bound_addr)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Proxy {
+    /// Issues a BIND request through an already established connection to this proxy,
+    /// only socks5 proxies support it
+    pub async fn bind_with_stream(
+        &self,
+        stream: ProxyStream,
+        target_host: &str,
+        target_port: u16,
+    ) -> Result<PendingBind, ProxyError> {
+        if self.protocol != ProxyProtocol::Socks5 {
+            return Err(ProxyError::BindNotSupported(self.to_string()));
+        }
+
+        let mut socks5_stream =
+            Socks5Stream::use_stream(stream, self.socks5_auth(), Socks5Config::default()).await?;
+        let bound_addr = socks5_stream
+            .request(
+                Socks5Command::TCPBind,
+                target_addr(target_host, target_port),
+            )
+            .await?;
+
+        Ok(PendingBind {
+            stream: socks5_stream.get_socket(),
+            bound_addr,
+        })
+    }
+
+    /// Opens a UDP association, only socks5 proxies are able to relay UDP
+    pub async fn udp_associate(&self) -> Result<UdpAssociation, ProxyError> {
+        if self.protocol != ProxyProtocol::Socks5 {
+            return Err(ProxyError::UdpNotSupported(self.to_string()));
+        }
+
+        let stream = self.dial().await?;
+        let proxy_ip = stream.peer_addr().map_err(SocksError::Io)?.ip();
+        let local_ip = stream.local_addr().map_err(SocksError::Io)?.ip();
+        let socket = UdpSocket::bind(SocketAddr::new(local_ip, 0))
+            .await
+            .map_err(SocksError::Io)?;
+        let local_addr = socket.local_addr().map_err(SocksError::Io)?;
+        let mut socks5_stream =
+            Socks5Stream::use_stream(stream, self.socks5_auth(), Socks5Config::default()).await?;
+        let relay_addr = match socks5_stream
+            .request(Socks5Command::UDPAssociate, TargetAddr::Ip(local_addr))
+            .await?
+        {
+            // An unspecified address means the relay is on the proxy host
+            TargetAddr::Ip(addr) if addr.ip().is_unspecified() => {
+                SocketAddr::new(proxy_ip, addr.port())
+            }
+            TargetAddr::Ip(addr) => addr,
+            TargetAddr::Domain(domain, port) => resolve_host(&domain, port).await?,
+        };
+
+        socket.connect(relay_addr).await.map_err(SocksError::Io)?;
+
+        Ok(UdpAssociation {
+            control: socks5_stream.get_socket(),
+            socket,
+            dns_resolution: self.dns_resolution,
+        })
+    }
+}
+
+impl ProxyChain {
+    /// Issues a BIND request on the last hop, which must be a socks5 proxy
+    pub async fn bind(
+        &self,
+        target_host: &str,
+        target_port: u16,
+    ) -> Result<PendingBind, ProxyError> {
+        let (stream, last_hop) = self.connect_last_hop().await?;
+
+        last_hop
+            .bind_with_stream(stream, target_host, target_port)
+            .await
+    }
+
+    /// Opens a UDP association, datagrams can't be tunneled so only single socks5 proxies are supported
+    pub async fn udp_associate(&self) -> Result<UdpAssociation, ProxyError> {
+        match self.hops() {
+            [proxy] => proxy.udp_associate().await,
+            [] => Err(ProxyError::EmptyChain),
+            _ => Err(ProxyError::UdpNotSupported(self.to_string())),
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use super::ProxyError;
+use derive_builder::Builder;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::crypto::{
+    ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider,
+};
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use tokio_rustls::rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use tokio_rustls::TlsConnector;
+
+#[derive(Debug, Clone, Default, Builder)]
+#[builder(build_fn(private, name = "build_options"))]
+pub struct TlsOptions {
+    /// Overrides the server name sent in the SNI extension and used for certificate verification
+    #[builder(setter(into, strip_option), default)]
+    server_name: Option<String>,
+    /// PEM bundle with the CA certificates trusted in addition to the built-in roots
+    #[builder(setter(into, strip_option), default)]
+    ca_file: Option<PathBuf>,
+    /// Disables verification of the proxy certificate
+    #[builder(default)]
+    insecure: bool,
+    /// Built once with the options, shared by all of the connections
+    #[builder(setter(skip))]
+    client_config: Option<Arc<ClientConfig>>,
+}
+
+impl PartialEq for TlsOptions {
+    fn eq(&self, other: &Self) -> bool {
+        self.server_name == other.server_name
+            && self.ca_file == other.ca_file
+            && self.insecure == other.insecure
+    }
+}
+
+impl TlsOptionsBuilder {
+    /// Fails if the CA file can't be read
+    pub fn build(&self) -> Result<TlsOptions, TlsOptionsBuilderError> {
+        let mut options = self.build_options()?;
+        let client_config = options
+            .load_client_config()
+            .map_err(|err| TlsOptionsBuilderError::ValidationError(err.to_string()))?;
+
+        options.client_config = Some(Arc::new(client_config));
+
+        Ok(options)
+    }
+}
+
+impl TlsOptions {
+    pub fn builder() -> TlsOptionsBuilder {
+        TlsOptionsBuilder::default()
+    }
+
+    fn client_config(&self) -> Result<Arc<ClientConfig>, ProxyError> {
+        // Only the default options are built without the config, so they share a single one
+        static DEFAULT_CLIENT_CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+
+        if let Some(client_config) = &self.client_config {
+            return Ok(client_config.clone());
+        }
+
+        if let Some(client_config) = DEFAULT_CLIENT_CONFIG.get() {
+            return Ok(client_config.clone());
+        }
+
+        let client_config = Arc::new(self.load_client_config()?);
+
+        Ok(DEFAULT_CLIENT_CONFIG.get_or_init(|| client_config).clone())
+    }
+
+    fn load_client_config(&self) -> Result<ClientConfig, ProxyError> {
+        let provider = Arc::new(ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|err| ProxyError::InvalidTlsConfig(err.to_string()))?;
+
+        if self.insecure {
+            return Ok(builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(NoCertificateVerification(provider)))
+                .with_no_client_auth());
+        }
+
+        let mut root_store = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+
+        if let Some(ca_file) = &self.ca_file {
+            let file = File::open(ca_file).map_err(|err| {
+                ProxyError::InvalidTlsConfig(format!("Can't open {}: {}", ca_file.display(), err))
+            })?;
+
+            for cert in rustls_pemfile::certs(&mut BufReader::new(file)) {
+                let cert = cert.map_err(|err| {
+                    ProxyError::InvalidTlsConfig(format!(
+                        "Can't read {}: {}",
+                        ca_file.display(),
+                        err
+                    ))
+                })?;
+
+                root_store
+                    .add(cert)
+                    .map_err(|err| ProxyError::InvalidTlsConfig(err.to_string()))?;
+            }
+        }
+
+        Ok(builder
+            .with_root_certificates(root_store)
+            .with_no_client_auth())
+    }
+}
+
+pub(crate) async fn connect_tls<S>(
+    stream: S,
+    host: &str,
+    options: &TlsOptions,
+) -> Result<TlsStream<S>, ProxyError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let server_name = options
+        .server_name
+        .as_deref()
+        .unwrap_or(host)
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string();
+    let server_name = ServerName::try_from(server_name)
+        .map_err(|err| ProxyError::InvalidTlsConfig(err.to_string()))?;
+    let connector = TlsConnector::from(options.client_config()?);
+
+    connector
+        .connect(server_name, stream)
+        .await
+        .map_err(ProxyError::TlsError)
+}
+
+#[derive(Debug)]
+struct NoCertificateVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
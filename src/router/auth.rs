@@ -0,0 +1,201 @@
+use crate::proxy::SessionParams;
+use crate::router::session::UsernameGrammar;
+use async_trait::async_trait;
+use fast_socks5::server::Authentication;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::path::Path;
+use std::sync::Arc;
+use subtle::ConstantTimeEq;
+use thiserror::Error;
+use tokio::task;
+
+/// Validates the credentials sent by the router clients
+pub trait Authenticator: Debug + Send + Sync {
+    fn authenticate(&self, username: &str, password: &str) -> bool;
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MemoryAuthenticator {
+    users: HashMap<String, String>,
+}
+
+impl MemoryAuthenticator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_user(mut self, username: impl Into<String>, password: impl Into<String>) -> Self {
+        self.add_user(username, password);
+        self
+    }
+
+    pub fn add_user(&mut self, username: impl Into<String>, password: impl Into<String>) {
+        self.users.insert(username.into(), password.into());
+    }
+}
+
+impl Authenticator for MemoryAuthenticator {
+    fn authenticate(&self, username: &str, password: &str) -> bool {
+        let expected = self.users.get(username);
+        // Constant-time, so the password can't be guessed by the response time. Unknown usernames
+        // are compared too, so they can't be told apart from the known ones
+        let matches: bool = expected
+            .map_or(password, String::as_str)
+            .as_bytes()
+            .ct_eq(password.as_bytes())
+            .into();
+
+        expected.is_some() && matches
+    }
+}
+
+/// Users loaded from an htpasswd file (`username:hash` per line), passwords must be bcrypt hashed
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HtpasswdAuthenticator {
+    users: HashMap<String, String>,
+}
+
+impl HtpasswdAuthenticator {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, AuthError> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn parse(content: &str) -> Result<Self, AuthError> {
+        let mut users = HashMap::new();
+
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (username, hash) = line.split_once(':').ok_or(AuthError::InvalidLine(i + 1))?;
+
+            if !["$2a$", "$2b$", "$2x$", "$2y$"]
+                .iter()
+                .any(|prefix| hash.starts_with(prefix))
+            {
+                return Err(AuthError::UnsupportedHash(i + 1));
+            }
+
+            users.insert(username.to_string(), hash.to_string());
+        }
+
+        Ok(Self { users })
+    }
+}
+
+impl Authenticator for HtpasswdAuthenticator {
+    fn authenticate(&self, username: &str, password: &str) -> bool {
+        match self.users.get(username) {
+            Some(hash) => bcrypt::verify(password, hash).unwrap_or(false),
+            None => {
+                // Unknown usernames are verified against another hash, so they take as long as the known ones
+                if let Some(hash) = self.users.values().next() {
+                    let _ = bcrypt::verify(password, hash);
+                }
+
+                false
+            }
+        }
+    }
+}
+
+/// The authenticated user of a router connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    username: String,
+    session: SessionParams,
+}
+
+impl Identity {
+    pub fn new(username: impl Into<String>) -> Self {
+        Self {
+            username: username.into(),
+            session: SessionParams::default(),
+        }
+    }
+
+    pub fn with_session(mut self, session: SessionParams) -> Self {
+        self.session = session;
+        self
+    }
+
+    /// Parses the session parameters out of the username, if the grammar is set
+    pub(crate) fn from_username(username: &str, grammar: Option<&UsernameGrammar>) -> Self {
+        match grammar {
+            Some(grammar) => {
+                let (username, session) = grammar.parse(username);
+
+                Identity::new(username).with_session(session)
+            }
+            None => Identity::new(username),
+        }
+    }
+
+    /// The username without the session parameters
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    pub fn session(&self) -> &SessionParams {
+        &self.session
+    }
+}
+
+/// Adapts an `Authenticator` to the socks5 server
+#[derive(Debug, Clone)]
+pub(crate) struct RouterAuthentication {
+    authenticator: Arc<dyn Authenticator>,
+    username_grammar: Option<UsernameGrammar>,
+}
+
+impl RouterAuthentication {
+    pub(crate) fn new(
+        authenticator: Arc<dyn Authenticator>,
+        username_grammar: Option<UsernameGrammar>,
+    ) -> Self {
+        Self {
+            authenticator,
+            username_grammar,
+        }
+    }
+
+    /// Parses the session parameters out of the username, the remaining base username
+    /// is the one checked by the authenticator
+    pub(crate) fn identify(&self, username: &str) -> Identity {
+        Identity::from_username(username, self.username_grammar.as_ref())
+    }
+}
+
+#[async_trait]
+impl Authentication for RouterAuthentication {
+    type Item = Identity;
+
+    async fn authenticate(&self, credentials: Option<(String, String)>) -> Option<Identity> {
+        let (username, password) = credentials?;
+        let identity = self.identify(&username);
+        let authenticator = self.authenticator.clone();
+
+        // Password hashing is cpu-heavy, so it's kept off the async workers
+        task::spawn_blocking(move || {
+            authenticator
+                .authenticate(identity.username(), &password)
+                .then_some(identity)
+        })
+        .await
+        .unwrap_or(None)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum AuthError {
+    #[error("Io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid htpasswd line {0}")]
+    InvalidLine(usize),
+    #[error("Unsupported password hash on htpasswd line {0}, only bcrypt is supported")]
+    UnsupportedHash(usize),
+}
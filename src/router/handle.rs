@@ -0,0 +1,305 @@
+use super::listener::{accept, Listener, RouterStream};
+use super::RouterOptions;
+use crate::proxy::ProxyPool;
+use log::{debug, error, info};
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::task::{self, JoinError, JoinSet};
+use tokio::time::Instant;
+
+/// Controls a running router
+#[derive(Debug)]
+pub struct RouterHandle {
+    local_addrs: Vec<SocketAddr>,
+    options: Arc<RwLock<Arc<RouterOptions>>>,
+    counters: Arc<Counters>,
+    shutdown_tx: oneshot::Sender<Duration>,
+    join_handle: task::JoinHandle<ShutdownReport>,
+}
+
+impl RouterHandle {
+    /// The TCP addresses the router is bound to, with the actual ports of the ephemeral ones
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    /// The options applied to new connections
+    pub fn options(&self) -> Arc<RouterOptions> {
+        self.options.read().unwrap().clone()
+    }
+
+    /// Counters of the client connections
+    pub fn stats(&self) -> RouterStats {
+        RouterStats {
+            accepted: self.counters.accepted.load(Ordering::Relaxed),
+            rejected: self.counters.rejected.load(Ordering::Relaxed),
+            limited: self.counters.limited.load(Ordering::Relaxed),
+            active: self.counters.active.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Replaces the options of new connections, the active ones keep their original upstream.
+    ///
+    /// The listen addresses can't be changed on a running router, so they're ignored.
+    pub fn reconfigure(&self, mut options: RouterOptions) {
+        let mut current = self.options.write().unwrap();
+
+        options.carry_pool_counters(&current);
+        *current = Arc::new(options);
+    }
+
+    /// Replaces the default upstream of new connections
+    pub fn set_proxy(&self, proxy: impl Into<ProxyPool>) {
+        self.update(|options| options.proxy = proxy.into());
+    }
+
+    pub fn set_request_timeout(&self, request_timeout: Duration) {
+        self.update(|options| options.request_timeout = request_timeout);
+    }
+
+    pub fn set_connect_deadline(&self, connect_deadline: Option<Duration>) {
+        self.update(|options| options.connect_deadline = connect_deadline);
+    }
+
+    fn update(&self, update: impl FnOnce(&mut RouterOptions)) {
+        let mut current = self.options.write().unwrap();
+        let mut options = RouterOptions::clone(&current);
+
+        update(&mut options);
+        options.carry_pool_counters(&current);
+        *current = Arc::new(options);
+    }
+
+    /// Stops accepting new connections and waits up to `deadline` for the active ones to finish,
+    /// the remaining connections are closed
+    pub async fn shutdown(self, deadline: Duration) -> Result<ShutdownReport, JoinError> {
+        // The router task is gone if the send fails, the join handle tells why
+        _ = self.shutdown_tx.send(deadline);
+
+        self.join_handle.await
+    }
+
+    /// Closes the listener and all connections immediately
+    pub fn abort(&self) {
+        self.join_handle.abort();
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.join_handle.is_finished()
+    }
+}
+
+/// Connections of a router at the moment of the shutdown
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ShutdownReport {
+    drained: usize,
+    killed: usize,
+}
+
+impl ShutdownReport {
+    /// Connections finished before the deadline
+    pub fn drained(&self) -> usize {
+        self.drained
+    }
+
+    /// Connections closed at the deadline
+    pub fn killed(&self) -> usize {
+        self.killed
+    }
+}
+
+/// Client connections of a router since it was started
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RouterStats {
+    accepted: u64,
+    rejected: u64,
+    limited: u64,
+    active: usize,
+}
+
+impl RouterStats {
+    /// Connections handed to the handler
+    pub fn accepted(&self) -> u64 {
+        self.accepted
+    }
+
+    /// Connections closed by the client policy
+    pub fn rejected(&self) -> u64 {
+        self.rejected
+    }
+
+    /// Connections closed at the connection limits
+    pub fn limited(&self) -> u64 {
+        self.limited
+    }
+
+    /// Connections being handled at the moment
+    pub fn active(&self) -> usize {
+        self.active
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    accepted: AtomicU64,
+    rejected: AtomicU64,
+    limited: AtomicU64,
+    active: AtomicUsize,
+    active_per_client: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl Counters {
+    /// Counts the connection as active, unless it's over the limits of the options
+    fn enter(
+        self: &Arc<Self>,
+        client_ip: IpAddr,
+        options: &RouterOptions,
+    ) -> Option<ActiveConnection> {
+        let mut active_per_client = self.active_per_client.lock().unwrap();
+        let client_active = active_per_client.get(&client_ip).copied().unwrap_or(0);
+
+        if options
+            .max_connections
+            .is_some_and(|max| self.active.load(Ordering::Relaxed) >= max)
+            || options
+                .max_connections_per_client
+                .is_some_and(|max| client_active >= max)
+        {
+            return None;
+        }
+
+        active_per_client.insert(client_ip, client_active + 1);
+        self.active.fetch_add(1, Ordering::Relaxed);
+
+        Some(ActiveConnection {
+            counters: self.clone(),
+            client_ip,
+        })
+    }
+}
+
+/// Counted as active until dropped
+struct ActiveConnection {
+    counters: Arc<Counters>,
+    client_ip: IpAddr,
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        let mut active_per_client = self.counters.active_per_client.lock().unwrap();
+
+        if let Some(client_active) = active_per_client.get_mut(&self.client_ip) {
+            *client_active -= 1;
+
+            if *client_active == 0 {
+                active_per_client.remove(&self.client_ip);
+            }
+        }
+
+        self.counters.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Accepts the connections of the listeners until the shutdown, every connection is handled in its own task
+/// with the options current at the time it was accepted
+pub(crate) fn spawn_router<H, F>(
+    listeners: Vec<Listener>,
+    options: RouterOptions,
+    handler: H,
+) -> RouterHandle
+where
+    H: Fn(RouterStream, Arc<RouterOptions>) -> F + Send + 'static,
+    F: Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+    let local_addrs = listeners.iter().filter_map(Listener::local_addr).collect();
+    let options = Arc::new(RwLock::new(Arc::new(options)));
+    let current_options = options.clone();
+    let counters = Arc::new(Counters::default());
+    let router_counters = counters.clone();
+    let (shutdown_tx, mut shutdown_rx) = oneshot::channel();
+    let join_handle = task::spawn(async move {
+        let mut connections = JoinSet::new();
+        // A dropped handle detaches the router, it keeps running like a dropped `JoinHandle`
+        let mut detached = false;
+
+        let deadline = loop {
+            tokio::select! {
+                res = accept(&listeners) => match res {
+                    Ok(stream) => {
+                        let options = current_options.read().unwrap().clone();
+                        let peer_addr = stream.peer_addr();
+
+                        // Rejected clients are closed silently, before any handshake
+                        if !options.client_policy.is_allowed(peer_addr.ip()) {
+                            debug!("Rejected client {} by the client policy", peer_addr);
+                            router_counters.rejected.fetch_add(1, Ordering::Relaxed);
+                            continue;
+                        }
+
+                        let active = match router_counters.enter(peer_addr.ip(), &options) {
+                            Some(active) => active,
+                            None => {
+                                debug!("Closed client {} at the connection limit", peer_addr);
+                                router_counters.limited.fetch_add(1, Ordering::Relaxed);
+                                continue;
+                            }
+                        };
+
+                        router_counters.accepted.fetch_add(1, Ordering::Relaxed);
+
+                        let connection = handler(stream, options);
+
+                        connections.spawn(async move {
+                            let _active = active;
+
+                            if let Err(err) = connection.await {
+                                error!("Socket handle error: {:#}", err);
+                            }
+                        });
+                    }
+                    Err(err) => {
+                        error!("Socket accept error: {:#}", err);
+                    }
+                },
+                // Finished connections are reaped, so the set only holds the active ones
+                Some(_) = connections.join_next(), if !connections.is_empty() => (),
+                res = &mut shutdown_rx, if !detached => match res {
+                    Ok(deadline) => break deadline,
+                    Err(_) => detached = true,
+                },
+            }
+        };
+
+        drop(listeners);
+        info!(
+            "Router is shutting down, draining {} connections",
+            connections.len()
+        );
+
+        let deadline = Instant::now() + deadline;
+        let mut drained = 0;
+
+        while let Ok(Some(_)) = tokio::time::timeout_at(deadline, connections.join_next()).await {
+            drained += 1;
+        }
+
+        let killed = connections.len();
+
+        connections.shutdown().await;
+
+        ShutdownReport { drained, killed }
+    });
+
+    RouterHandle {
+        local_addrs,
+        options,
+        counters,
+        shutdown_tx,
+        join_handle,
+    }
+}
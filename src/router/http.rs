@@ -0,0 +1,569 @@
+use super::handle::spawn_router;
+use super::listener::RouterStream;
+use super::{connect_target, transfer, Client, Listener, RouterHandle};
+use crate::proxy::ProxyStream;
+use anyhow::anyhow;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use fast_socks5::server::Authentication;
+use fast_socks5::ReplyError;
+use log::debug;
+use std::sync::Arc;
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use url::Url;
+
+pub use super::{RouterOptions, RouterOptionsBuilder};
+
+const MAX_HEAD_SIZE: u64 = 16 * 1024;
+
+/// Spawns an HTTP proxy accepting `CONNECT` and absolute-form plain HTTP requests
+pub async fn spawn_http_router(options: RouterOptions) -> anyhow::Result<RouterHandle> {
+    let listeners = options.bind_listeners()?;
+
+    Ok(spawn_http_router_with_listeners(listeners, options))
+}
+
+/// Serves the connections of the given listeners instead of binding the listen addresses of the options
+pub fn spawn_http_router_with_listeners(
+    listeners: Vec<Listener>,
+    options: RouterOptions,
+) -> RouterHandle {
+    spawn_router(listeners, options, handle_socket)
+}
+
+pub(super) async fn handle_socket(
+    stream: RouterStream,
+    options: Arc<RouterOptions>,
+) -> anyhow::Result<()> {
+    let local_addr = stream.local_addr();
+    let peer_addr = stream.peer_addr();
+    // Bytes sent by the client right after the request head stay buffered and are relayed as well
+    let mut socket = BufReader::new(stream);
+    let request =
+        match tokio::time::timeout(options.request_timeout, read_request(&mut socket)).await {
+            Ok(Ok(request)) => request,
+            Ok(Err(err)) => {
+                write_error_reply(&mut socket, 400, "Bad Request", &[]).await?;
+
+                return Err(err);
+            }
+            Err(_) => {
+                write_error_reply(&mut socket, 408, "Request Timeout", &[]).await?;
+
+                return Err(anyhow!("Request timeout"));
+            }
+        };
+    let identity = match options.authentication() {
+        Some(authentication) => {
+            let identity = match request.credentials() {
+                Some(credentials) => authentication.authenticate(Some(credentials)).await,
+                None => None,
+            };
+
+            if identity.is_none() {
+                write_error_reply(
+                    &mut socket,
+                    407,
+                    "Proxy Authentication Required",
+                    &["Proxy-Authenticate: Basic realm=\"proxy-router\""],
+                )
+                .await?;
+
+                return Ok(());
+            }
+
+            identity
+        }
+        None => None,
+    };
+    let client = Client {
+        local_addr,
+        peer_addr,
+        identity,
+    };
+
+    match execute_request(&mut socket, request, &options, &client).await {
+        Ok(_) => Ok(()),
+        Err(RequestError::Reply(err)) => {
+            let (code, reason) = status_from_reply_error(&err);
+
+            write_error_reply(&mut socket, code, reason, &[]).await?;
+
+            Err(anyhow!("{}", err))
+        }
+        Err(RequestError::BadRequest(message)) => {
+            write_error_reply(&mut socket, 400, "Bad Request", &[]).await?;
+
+            Err(anyhow!("Bad request: {}", message))
+        }
+        Err(RequestError::Io(err)) => Err(err.into()),
+    }
+}
+
+enum RequestError {
+    Reply(ReplyError),
+    BadRequest(&'static str),
+    Io(std::io::Error),
+}
+
+impl From<std::io::Error> for RequestError {
+    fn from(err: std::io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+async fn execute_request(
+    socket: &mut BufReader<RouterStream>,
+    request: Request,
+    options: &RouterOptions,
+    client: &Client,
+) -> Result<(), RequestError> {
+    let (target_host, target_port, forwarded_head) = match request.method.as_str() {
+        "CONNECT" => {
+            let (host, port) =
+                parse_authority(&request.target).ok_or(RequestError::BadRequest("authority"))?;
+
+            (host, port, None)
+        }
+        _ => {
+            let url = Url::parse(&request.target)
+                .map_err(|_| RequestError::BadRequest("absolute-form target"))?;
+
+            if url.scheme() != "http" {
+                return Err(RequestError::BadRequest("scheme"));
+            }
+
+            let host = url
+                .host_str()
+                .ok_or(RequestError::BadRequest("host"))?
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string();
+            let port = url.port_or_known_default().unwrap_or(80);
+            let body = request.body()?;
+
+            (host, port, Some((request.origin_form_head(&url), body)))
+        }
+    };
+    let (_lease, mut downstream) = connect_target(options, client, target_host, target_port)
+        .await
+        .map_err(RequestError::Reply)?;
+
+    match forwarded_head {
+        Some((head, body)) => {
+            downstream.write_all(head.as_bytes()).await?;
+
+            forward_request(socket, &mut downstream, body).await
+        }
+        None => {
+            socket
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .await?;
+            socket.flush().await?;
+
+            Ok(transfer(&mut downstream, socket).await?)
+        }
+    }
+}
+
+/// Relays the body of a plain HTTP request and the response of the origin server.
+///
+/// Nothing after the body is relayed, the next requests of the client may go to another host
+/// and the connection is closed after the response.
+async fn forward_request(
+    socket: &mut BufReader<RouterStream>,
+    downstream: &mut ProxyStream,
+    body: Body,
+) -> Result<(), RequestError> {
+    let (client_reader, mut client_writer) = tokio::io::split(socket);
+    let (mut downstream_reader, mut downstream_writer) = tokio::io::split(downstream);
+    // The response is relayed while the body is sent, e.g. for "Expect: 100-continue"
+    let send_body = async {
+        relay_body(
+            &mut BufReader::new(client_reader),
+            &mut downstream_writer,
+            body,
+        )
+        .await?;
+        downstream_writer.flush().await?;
+
+        Ok::<_, RequestError>(())
+    };
+    let relay_response = async {
+        tokio::io::copy(&mut downstream_reader, &mut client_writer).await?;
+        client_writer.flush().await
+    };
+
+    tokio::pin!(relay_response);
+
+    tokio::select! {
+        res = send_body => {
+            res?;
+            (&mut relay_response).await?;
+        }
+        // The origin server may respond before the body is finished
+        res = &mut relay_response => res?,
+    }
+
+    debug!("Plain HTTP request finished");
+
+    Ok(())
+}
+
+/// Length of the request body, as framed by the headers
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Body {
+    Length(u64),
+    Chunked,
+}
+
+/// Copies the request body to the writer, the bytes after the body are left unread
+async fn relay_body<R, W>(reader: &mut R, writer: &mut W, body: Body) -> Result<(), RequestError>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    match body {
+        Body::Length(len) => copy_exact(reader, writer, len).await,
+        Body::Chunked => {
+            loop {
+                let line = relay_line(reader, writer).await?;
+                let size = line.split(';').next().unwrap_or_default().trim();
+                let size = u64::from_str_radix(size, 16)
+                    .map_err(|_| RequestError::BadRequest("chunk size"))?;
+
+                if size == 0 {
+                    break;
+                }
+
+                // Each chunk ends with CRLF
+                copy_exact(reader, writer, size + 2).await?;
+            }
+
+            // Trailer fields, up to the empty line
+            while !relay_line(reader, writer).await?.is_empty() {}
+
+            Ok(())
+        }
+    }
+}
+
+/// Copies a line of the chunked body, the line is returned without the line break
+async fn relay_line<R, W>(reader: &mut R, writer: &mut W) -> Result<String, RequestError>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut line = String::new();
+
+    if (&mut *reader)
+        .take(MAX_HEAD_SIZE)
+        .read_line(&mut line)
+        .await?
+        == 0
+    {
+        return Err(RequestError::BadRequest("incomplete chunked body"));
+    }
+
+    writer.write_all(line.as_bytes()).await?;
+
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+async fn copy_exact<R, W>(reader: &mut R, writer: &mut W, len: u64) -> Result<(), RequestError>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    if tokio::io::copy(&mut (&mut *reader).take(len), writer).await? < len {
+        return Err(RequestError::BadRequest("incomplete body"));
+    }
+
+    Ok(())
+}
+
+#[derive(Debug)]
+struct Request {
+    method: String,
+    target: String,
+    version: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Framing of the body, requests without a length header have no body
+    fn body(&self) -> Result<Body, RequestError> {
+        if let Some(encoding) = self.header("Transfer-Encoding") {
+            // Chunked must be the last coding, otherwise the end of the body is unknown
+            return match encoding.rsplit(',').next() {
+                Some(coding) if coding.trim().eq_ignore_ascii_case("chunked") => Ok(Body::Chunked),
+                _ => Err(RequestError::BadRequest("transfer encoding")),
+            };
+        }
+
+        match self.header("Content-Length") {
+            Some(len) => len
+                .parse()
+                .map(Body::Length)
+                .map_err(|_| RequestError::BadRequest("content length")),
+            None => Ok(Body::Length(0)),
+        }
+    }
+
+    /// Credentials of the `Proxy-Authorization: Basic` header
+    fn credentials(&self) -> Option<(String, String)> {
+        let value = self.header("Proxy-Authorization")?;
+        let (scheme, encoded) = value.split_once(' ')?;
+
+        if !scheme.eq_ignore_ascii_case("Basic") {
+            return None;
+        }
+
+        let decoded = String::from_utf8(BASE64.decode(encoded.trim()).ok()?).ok()?;
+        let (username, password) = decoded.split_once(':')?;
+
+        Some((username.to_string(), password.to_string()))
+    }
+
+    /// The request head for the origin server, hop-by-hop proxy headers are dropped.
+    ///
+    /// The connection is closed after the response, since the next request may go to another host.
+    fn origin_form_head(&self, url: &Url) -> String {
+        let path = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        };
+        let mut head = format!("{} {} {}\r\n", self.method, path, self.version);
+
+        if self.header("Host").is_none() {
+            head.push_str(&format!("Host: {}\r\n", &self.target_authority(url)));
+        }
+
+        for (key, value) in &self.headers {
+            if [
+                "Proxy-Authorization",
+                "Proxy-Connection",
+                "Connection",
+                "Keep-Alive",
+            ]
+            .iter()
+            .any(|hop_header| key.eq_ignore_ascii_case(hop_header))
+            {
+                continue;
+            }
+
+            head.push_str(&format!("{}: {}\r\n", key, value));
+        }
+
+        head.push_str("Connection: close\r\n\r\n");
+        head
+    }
+
+    fn target_authority(&self, url: &Url) -> String {
+        let host = url.host_str().unwrap_or_default();
+
+        match url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        }
+    }
+}
+
+/// Reads the request line and the headers, up to the empty line
+async fn read_request<R>(reader: &mut R) -> anyhow::Result<Request>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut head = (&mut *reader).take(MAX_HEAD_SIZE);
+    let mut lines = Vec::new();
+
+    loop {
+        let mut line = String::new();
+
+        if head.read_line(&mut line).await? == 0 {
+            return Err(anyhow!("Incomplete request head"));
+        }
+
+        let line = line.trim_end_matches(['\r', '\n']);
+
+        if line.is_empty() {
+            // Empty lines before the request line are ignored
+            if lines.is_empty() {
+                continue;
+            }
+
+            break;
+        }
+
+        lines.push(line.to_string());
+    }
+
+    let mut request_line = lines[0].split_whitespace();
+    let (method, target, version) = match (
+        request_line.next(),
+        request_line.next(),
+        request_line.next(),
+        request_line.next(),
+    ) {
+        (Some(method), Some(target), Some(version), None) if version.starts_with("HTTP/1.") => {
+            (method, target, version)
+        }
+        _ => return Err(anyhow!("Invalid request line: {}", lines[0])),
+    };
+    let headers = lines[1..]
+        .iter()
+        .map(|line| {
+            line.split_once(':')
+                .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+                .ok_or_else(|| anyhow!("Invalid header line: {}", line))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    Ok(Request {
+        method: method.to_string(),
+        target: target.to_string(),
+        version: version.to_string(),
+        headers,
+    })
+}
+
+/// Splits `host:port` of a CONNECT request, IPv6 hosts are enclosed in brackets
+fn parse_authority(authority: &str) -> Option<(String, u16)> {
+    let (host, port) = authority.rsplit_once(':')?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    if host.is_empty() {
+        return None;
+    }
+
+    Some((host.to_string(), port.parse().ok()?))
+}
+
+fn status_from_reply_error(err: &ReplyError) -> (u16, &'static str) {
+    match err {
+        ReplyError::ConnectionTimeout | ReplyError::TtlExpired => (504, "Gateway Timeout"),
+        ReplyError::ConnectionNotAllowed => (403, "Forbidden"),
+        ReplyError::CommandNotSupported | ReplyError::AddressTypeNotSupported => {
+            (501, "Not Implemented")
+        }
+        _ => (502, "Bad Gateway"),
+    }
+}
+
+async fn write_error_reply(
+    socket: &mut BufReader<RouterStream>,
+    code: u16,
+    reason: &str,
+    headers: &[&str],
+) -> std::io::Result<()> {
+    let mut reply = format!("HTTP/1.1 {} {}\r\n", code, reason);
+
+    for header in headers {
+        reply.push_str(header);
+        reply.push_str("\r\n");
+    }
+
+    reply.push_str("Content-Length: 0\r\nConnection: close\r\n\r\n");
+
+    socket.write_all(reply.as_bytes()).await?;
+    socket.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn parse_request(head: &str) -> Request {
+        read_request(&mut head.as_bytes()).await.unwrap()
+    }
+
+    async fn relayed_body(data: &[u8], body: Body) -> (Vec<u8>, Vec<u8>) {
+        let mut reader = data;
+        let mut relayed = Vec::new();
+
+        assert!(relay_body(&mut reader, &mut relayed, body).await.is_ok());
+
+        (relayed, reader.to_vec())
+    }
+
+    #[tokio::test]
+    async fn request_body_framing() {
+        let request = parse_request("GET http://example.com/ HTTP/1.1\r\n\r\n").await;
+        assert_eq!(request.body().ok(), Some(Body::Length(0)));
+
+        let request =
+            parse_request("POST http://example.com/ HTTP/1.1\r\nContent-Length: 5\r\n\r\n").await;
+        assert_eq!(request.body().ok(), Some(Body::Length(5)));
+
+        let request = parse_request(concat!(
+            "POST http://example.com/ HTTP/1.1\r\n",
+            "Content-Length: 5\r\n",
+            "Transfer-Encoding: gzip, chunked\r\n\r\n",
+        ))
+        .await;
+        assert_eq!(request.body().ok(), Some(Body::Chunked));
+
+        let request = parse_request(
+            "POST http://example.com/ HTTP/1.1\r\nTransfer-Encoding: chunked, gzip\r\n\r\n",
+        )
+        .await;
+        assert!(request.body().is_err());
+
+        let request =
+            parse_request("POST http://example.com/ HTTP/1.1\r\nContent-Length: -1\r\n\r\n").await;
+        assert!(request.body().is_err());
+    }
+
+    #[tokio::test]
+    async fn relay_body_stops_at_the_next_request() {
+        let next_request =
+            b"GET http://other.example/ HTTP/1.1\r\nProxy-Authorization: Basic dTpw\r\n\r\n";
+
+        let data = [b"hello".as_slice(), next_request].concat();
+        let (relayed, rest) = relayed_body(&data, Body::Length(5)).await;
+        assert_eq!(relayed, b"hello");
+        assert_eq!(rest, next_request);
+
+        let (relayed, rest) = relayed_body(next_request, Body::Length(0)).await;
+        assert!(relayed.is_empty());
+        assert_eq!(rest, next_request);
+
+        let chunked = b"5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nTrailer: 1\r\n\r\n".as_slice();
+        let data = [chunked, next_request].concat();
+        let (relayed, rest) = relayed_body(&data, Body::Chunked).await;
+        assert_eq!(relayed, chunked);
+        assert_eq!(rest, next_request);
+    }
+
+    #[tokio::test]
+    async fn relay_body_rejects_incomplete_bodies() {
+        let mut relayed = Vec::new();
+
+        assert!(
+            relay_body(&mut b"hel".as_slice(), &mut relayed, Body::Length(5))
+                .await
+                .is_err()
+        );
+        assert!(relay_body(
+            &mut b"5\r\nhello\r\n".as_slice(),
+            &mut relayed,
+            Body::Chunked
+        )
+        .await
+        .is_err());
+        assert!(
+            relay_body(&mut b"x\r\n".as_slice(), &mut relayed, Body::Chunked)
+                .await
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,307 @@
+use listenfd::ListenFd;
+use std::future;
+use std::io::{self, IoSlice};
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+/// A listener accepting the router clients
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixSocketListener),
+}
+
+impl Listener {
+    /// Binds a Unix socket, which is removed when the listener is dropped.
+    ///
+    /// A socket file left by a crashed process is replaced, a socket in use is not.
+    #[cfg(unix)]
+    pub fn bind_unix(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+
+        remove_stale_socket(path)?;
+
+        Ok(Self::Unix(UnixSocketListener {
+            listener: UnixListener::bind(path)?,
+            path: Some(path.to_path_buf()),
+        }))
+    }
+
+    /// Takes the sockets passed by systemd socket activation (`LISTEN_FDS`)
+    pub fn from_systemd() -> io::Result<Vec<Self>> {
+        let mut listenfd = ListenFd::from_env();
+        let mut listeners = Vec::with_capacity(listenfd.len());
+
+        for i in 0..listenfd.len() {
+            let listener = match listenfd.take_tcp_listener(i) {
+                Ok(Some(listener)) => {
+                    listener.set_nonblocking(true)?;
+                    Self::Tcp(TcpListener::from_std(listener)?)
+                }
+                Ok(None) => continue,
+                #[cfg(unix)]
+                Err(_) => match listenfd.take_unix_listener(i)? {
+                    Some(listener) => {
+                        listener.set_nonblocking(true)?;
+                        Self::Unix(UnixListener::from_std(listener)?.into())
+                    }
+                    None => continue,
+                },
+                #[cfg(not(unix))]
+                Err(err) => return Err(err),
+            };
+
+            listeners.push(listener);
+        }
+
+        Ok(listeners)
+    }
+
+    /// The address of a TCP listener, Unix listeners have none
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Self::Tcp(listener) => listener.local_addr().ok(),
+            #[cfg(unix)]
+            Self::Unix(_) => None,
+        }
+    }
+
+    fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<io::Result<RouterStream>> {
+        match self {
+            Self::Tcp(listener) => match listener.poll_accept(cx) {
+                Poll::Ready(Ok((stream, _))) => Poll::Ready(RouterStream::tcp(stream)),
+                Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+                Poll::Pending => Poll::Pending,
+            },
+            #[cfg(unix)]
+            Self::Unix(listener) => match listener.listener.poll_accept(cx) {
+                Poll::Ready(Ok((stream, _))) => Poll::Ready(Ok(RouterStream::unix(stream))),
+                Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+                Poll::Pending => Poll::Pending,
+            },
+        }
+    }
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Self::Tcp(listener)
+    }
+}
+
+#[cfg(unix)]
+impl From<UnixListener> for Listener {
+    fn from(listener: UnixListener) -> Self {
+        Self::Unix(listener.into())
+    }
+}
+
+/// A Unix socket listener, the socket file is removed on drop if it was bound by the router
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct UnixSocketListener {
+    listener: UnixListener,
+    path: Option<PathBuf>,
+}
+
+#[cfg(unix)]
+impl From<UnixListener> for UnixSocketListener {
+    /// The socket file is left to the owner of the listener
+    fn from(listener: UnixListener) -> Self {
+        Self {
+            listener,
+            path: None,
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for UnixSocketListener {
+    fn drop(&mut self) {
+        if let Some(path) = &self.path {
+            _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Removes the socket file if no process accepts its connections anymore
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            match std::os::unix::net::UnixStream::connect(path) {
+                Ok(_) => Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("{} is in use", path.display()),
+                )),
+                Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => {
+                    std::fs::remove_file(path)
+                }
+                Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+                Err(err) => Err(err),
+            }
+        }
+        // Other files are left as is, so the bind fails on them
+        _ => Ok(()),
+    }
+}
+
+/// Accepts a connection from any of the listeners
+pub(crate) async fn accept(listeners: &[Listener]) -> io::Result<RouterStream> {
+    future::poll_fn(|cx| {
+        for listener in listeners {
+            if let Poll::Ready(res) = listener.poll_accept(cx) {
+                return Poll::Ready(res);
+            }
+        }
+
+        Poll::Pending
+    })
+    .await
+}
+
+#[derive(Debug)]
+enum Connection {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+/// A client connection of the router.
+///
+/// Unix socket clients are local, so they get loopback addresses.
+#[derive(Debug)]
+pub(crate) struct RouterStream {
+    connection: Connection,
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+    /// A byte read by `peek`, returned by the next read
+    peeked: Option<u8>,
+}
+
+impl RouterStream {
+    fn tcp(stream: TcpStream) -> io::Result<Self> {
+        Ok(Self {
+            local_addr: stream.local_addr()?,
+            peer_addr: stream.peer_addr()?,
+            connection: Connection::Tcp(stream),
+            peeked: None,
+        })
+    }
+
+    #[cfg(unix)]
+    fn unix(stream: UnixStream) -> Self {
+        Self {
+            connection: Connection::Unix(stream),
+            local_addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+            peer_addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+            peeked: None,
+        }
+    }
+
+    pub(crate) fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub(crate) fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    /// Returns the first byte without consuming it, `None` if the client closed the connection
+    pub(crate) async fn peek(&mut self) -> io::Result<Option<u8>> {
+        if self.peeked.is_none() {
+            let mut byte = [0u8; 1];
+
+            if self.read(&mut byte).await? == 0 {
+                return Ok(None);
+            }
+
+            self.peeked = Some(byte[0]);
+        }
+
+        Ok(self.peeked)
+    }
+}
+
+impl AsyncRead for RouterStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if let Some(byte) = self.peeked {
+            if buf.remaining() > 0 {
+                buf.put_slice(&[byte]);
+                self.peeked = None;
+            }
+
+            return Poll::Ready(Ok(()));
+        }
+
+        match &mut self.connection {
+            Connection::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for RouterStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match &mut self.connection {
+            Connection::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.connection {
+            Connection::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            Connection::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.connection {
+            Connection::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            Connection::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match &mut self.connection {
+            Connection::Tcp(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            #[cfg(unix)]
+            Connection::Unix(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match &self.connection {
+            Connection::Tcp(stream) => stream.is_write_vectored(),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.is_write_vectored(),
+        }
+    }
+}
@@ -0,0 +1,43 @@
+use super::handle::spawn_router;
+use super::listener::RouterStream;
+use super::{http, socks4, socks5, Listener, RouterHandle};
+use anyhow::anyhow;
+use std::sync::Arc;
+
+pub use super::{RouterOptions, RouterOptionsBuilder};
+
+/// Spawns a router serving socks4/4a, socks5 and HTTP proxy clients on the same port.
+///
+/// The protocol is detected by the first byte of every connection.
+pub async fn spawn_mixed_router(options: RouterOptions) -> anyhow::Result<RouterHandle> {
+    let listeners = options.bind_listeners()?;
+
+    Ok(spawn_mixed_router_with_listeners(listeners, options))
+}
+
+/// Serves the connections of the given listeners instead of binding the listen addresses of the options
+pub fn spawn_mixed_router_with_listeners(
+    listeners: Vec<Listener>,
+    options: RouterOptions,
+) -> RouterHandle {
+    spawn_router(listeners, options, handle_socket)
+}
+
+async fn handle_socket(
+    mut stream: RouterStream,
+    options: Arc<RouterOptions>,
+) -> anyhow::Result<()> {
+    // The byte is only peeked, so the protocol handler reads the request from the start
+    let version = match tokio::time::timeout(options.request_timeout, stream.peek()).await {
+        Ok(Ok(Some(version))) => version,
+        Ok(Ok(None)) => return Ok(()),
+        Ok(Err(err)) => return Err(err.into()),
+        Err(_) => return Err(anyhow!("Request timeout")),
+    };
+
+    match version {
+        5 => Ok(socks5::handle_socket(stream, options).await?),
+        4 => socks4::handle_socket(stream, options).await,
+        _ => http::handle_socket(stream, options).await,
+    }
+}
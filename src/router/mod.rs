@@ -1 +1,640 @@
+use crate::proxy::{
+    resolve_host, DnsResolution, PoolLease, Proxy, ProxyError, ProxyPool, ProxyStream,
+    SessionParams, Socks4Error,
+};
+use crate::router::auth::{Authenticator, Identity, RouterAuthentication};
+use crate::router::policy::{AllowedHost, ClientPolicy, TargetPolicy};
+use crate::router::rules::{RouteAction, Rule, Target};
+use crate::router::session::UsernameGrammar;
+use anyhow::{bail, Context};
+use async_http_proxy::HttpError;
+use derive_builder::Builder;
+use fast_socks5::{ReplyError, SocksError};
+use log::{debug, info, warn};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+
+pub mod auth;
+mod handle;
+pub mod http;
+mod listener;
+pub mod mixed;
+pub mod policy;
+pub mod rules;
+pub mod session;
+pub mod socks4;
 pub mod socks5;
+
+pub use handle::{RouterHandle, RouterStats, ShutdownReport};
+pub use listener::Listener;
+#[cfg(unix)]
+pub use listener::UnixSocketListener;
+
+#[derive(Debug, Clone, Default, Builder)]
+#[builder(setter(strip_option), build_fn(validate = "Self::validate"))]
+pub struct RouterOptions {
+    /// Upstream of the clients without an own route.
+    ///
+    /// Required, unless a rule without matchers or the user routes of the authenticated clients route
+    /// the requests. The users without a route are rejected then.
+    #[builder(setter(into), default)]
+    proxy: ProxyPool,
+    /// IPv6 addresses accept only IPv6 clients, dual-stack routers list an IPv4 address as well.
+    ///
+    /// Port 0 binds an ephemeral port, the bound addresses are reported by the router handle.
+    #[builder(setter(each(name = "listen_addr", into)), default)]
+    listen_addrs: Vec<SocketAddr>,
+    /// Paths of the Unix sockets to listen on
+    #[builder(setter(each(name = "listen_path", into)), default)]
+    listen_paths: Vec<PathBuf>,
+    #[builder(default = "Duration::from_secs(10)")]
+    request_timeout: Duration,
+    #[builder(default)]
+    dns_resolution: DnsResolution,
+    /// How many upstreams are tried before an error is replied to the client
+    #[builder(default = "1")]
+    connect_attempts: usize,
+    /// Total time limit for all of the connect attempts
+    #[builder(default)]
+    connect_deadline: Option<Duration>,
+    /// Clients must authenticate with username/password when set
+    #[builder(setter(custom), default)]
+    authenticator: Option<Arc<dyn Authenticator>>,
+    /// Upstreams of the authenticated users, by username
+    #[builder(setter(custom), default)]
+    user_routes: HashMap<String, ProxyPool>,
+    /// Grammar of the session parameters in the client usernames, no parameters are parsed when unset
+    #[builder(default)]
+    username_grammar: Option<UsernameGrammar>,
+    /// Routing rules of the CONNECT requests, the first matching rule wins.
+    ///
+    /// Requests matching no rule go to the upstream of the client. BIND and UDP targets are matched as well,
+    /// but only the rejecting rules apply to them, they always go to the upstream of the client.
+    #[builder(setter(each(name = "rule")), default)]
+    rules: Vec<Rule>,
+    /// Targets the clients may connect to, the private networks are denied by default
+    #[builder(default)]
+    target_policy: TargetPolicy,
+    /// Clients accepted by the router, others are closed right after accept
+    #[builder(default)]
+    client_policy: ClientPolicy,
+    /// Limit of the active client connections, the connections over it are closed right after accept
+    #[builder(default)]
+    max_connections: Option<usize>,
+    /// Limit of the active connections of a single client IP
+    #[builder(default)]
+    max_connections_per_client: Option<usize>,
+}
+
+impl RouterOptions {
+    pub fn builder() -> RouterOptionsBuilder {
+        RouterOptionsBuilder::default()
+    }
+
+    fn bind_listeners(&self) -> anyhow::Result<Vec<Listener>> {
+        if self.listen_addrs.is_empty() && self.listen_paths.is_empty() {
+            bail!("No listen address");
+        }
+
+        let mut listeners = Vec::with_capacity(self.listen_addrs.len() + self.listen_paths.len());
+
+        for addr in &self.listen_addrs {
+            let listener =
+                bind_listener(*addr).context(format!("Can't bind the router to {}", addr))?;
+
+            listeners.push(Listener::Tcp(listener));
+        }
+
+        for path in &self.listen_paths {
+            #[cfg(unix)]
+            listeners.push(
+                Listener::bind_unix(path)
+                    .context(format!("Can't bind the router to {}", path.display()))?,
+            );
+            #[cfg(not(unix))]
+            bail!(
+                "Can't bind the router to {}, Unix sockets are not supported",
+                path.display()
+            );
+        }
+
+        Ok(listeners)
+    }
+
+    /// The authentication of the clients, if enabled
+    fn authentication(&self) -> Option<RouterAuthentication> {
+        self.authenticator.as_ref().map(|authenticator| {
+            RouterAuthentication::new(authenticator.clone(), self.username_grammar.clone())
+        })
+    }
+
+    /// Keeps counting the connections of the previous pools towards the upstream limits of the new ones.
+    ///
+    /// Pools are matched by their role: the default upstream, the route of the same user or the rule
+    /// at the same position.
+    fn carry_pool_counters(&mut self, previous: &RouterOptions) {
+        self.proxy.carry_counters(&previous.proxy);
+
+        for (username, pool) in self.user_routes.iter_mut() {
+            if let Some(previous_pool) = previous.user_routes.get(username) {
+                pool.carry_counters(previous_pool);
+            }
+        }
+
+        for (rule, previous_rule) in self.rules.iter_mut().zip(&previous.rules) {
+            if let (RouteAction::Upstream(pool), RouteAction::Upstream(previous_pool)) =
+                (rule.action_mut(), previous_rule.action())
+            {
+                pool.carry_counters(previous_pool);
+            }
+        }
+    }
+
+    fn pool_for(&self, identity: Option<&Identity>) -> &ProxyPool {
+        identity
+            .and_then(|identity| self.user_routes.get(identity.username()))
+            .unwrap_or(&self.proxy)
+    }
+}
+
+impl RouterOptionsBuilder {
+    fn validate(&self) -> Result<(), String> {
+        let has_proxy = self.proxy.as_ref().is_some_and(|proxy| !proxy.is_empty());
+        let has_catch_all_rule = self.rules.iter().flatten().any(Rule::matches_all);
+        let has_user_routes = self.authenticator.as_ref().is_some_and(Option::is_some)
+            && self
+                .user_routes
+                .as_ref()
+                .is_some_and(|routes| !routes.is_empty());
+
+        match has_proxy || has_catch_all_rule || has_user_routes {
+            true => Ok(()),
+            false => Err("No upstream proxy, the proxy option is required".to_string()),
+        }
+    }
+
+    pub fn authenticator(&mut self, authenticator: impl Authenticator + 'static) -> &mut Self {
+        self.authenticator = Some(Some(Arc::new(authenticator)));
+        self
+    }
+
+    /// Routes the connections of the user through its own upstream (a single proxy, a chain or a pool)
+    pub fn user_route(
+        &mut self,
+        username: impl Into<String>,
+        upstream: impl Into<ProxyPool>,
+    ) -> &mut Self {
+        self.user_routes
+            .get_or_insert_with(HashMap::new)
+            .insert(username.into(), upstream.into());
+        self
+    }
+}
+
+/// The client side of a router connection
+#[derive(Debug)]
+struct Client {
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+    identity: Option<Identity>,
+}
+
+impl Client {
+    fn session(&self) -> SessionParams {
+        self.identity
+            .as_ref()
+            .map(Identity::session)
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+fn bind_listener(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+
+    // Otherwise the IPv6 wildcard would take the IPv4 port as well on some platforms
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+
+    // Same as tokio, on Windows it would let other sockets bind the port as well
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+
+    TcpListener::from_std(socket.into())
+}
+
+/// Resolves the target hostname if local resolution is set
+async fn resolve_target(
+    options: &RouterOptions,
+    target_host: String,
+    target_port: u16,
+) -> Result<String, ProxyError> {
+    match options.dns_resolution {
+        DnsResolution::Local if target_host.parse::<IpAddr>().is_err() => {
+            Ok(resolve_host(&target_host, target_port)
+                .await?
+                .ip()
+                .to_string())
+        }
+        _ => Ok(target_host),
+    }
+}
+
+/// The reply of the first rule matching the target, if it rejects the target
+fn rejected_by_rules(
+    options: &RouterOptions,
+    target_host: &str,
+    resolved_host: &str,
+    target_port: u16,
+) -> Option<ReplyError> {
+    let target = Target::new(target_host, resolved_host.parse().ok(), target_port);
+
+    match options.rules.iter().find(|rule| rule.matches(&target)) {
+        Some(rule) => match rule.action() {
+            RouteAction::Reject(err) => Some(err.clone()),
+            _ => None,
+        },
+        None => None,
+    }
+}
+
+/// Connects to the target by the route of the rules, errors are mapped to the reply of the client.
+///
+/// The lease of the upstream must be held until the transfer is finished, so the upstream is counted as busy.
+async fn connect_target(
+    options: &RouterOptions,
+    client: &Client,
+    target_host: String,
+    target_port: u16,
+) -> Result<(Option<PoolLease>, ProxyStream), ReplyError> {
+    let resolved_host = resolve_target(options, target_host.clone(), target_port)
+        .await
+        .map_err(map_proxy_connect_error)?;
+
+    let allowed_host = match options
+        .target_policy
+        .check_host(&resolved_host, target_port)
+        .await
+    {
+        Some(allowed_host) => allowed_host,
+        None => {
+            debug!(
+                "Denied {}:{} by the target policy",
+                target_host, target_port
+            );
+
+            return Err(ReplyError::ConnectionNotAllowed);
+        }
+    };
+
+    let target = Target::new(&target_host, resolved_host.parse().ok(), target_port);
+    let pool = match options.rules.iter().find(|rule| rule.matches(&target)) {
+        Some(rule) => match rule.action() {
+            RouteAction::Upstream(pool) => pool,
+            RouteAction::Direct => {
+                let direct_hosts = allowed_host.local_hosts(&resolved_host, false);
+
+                if direct_hosts.is_empty() {
+                    debug!(
+                        "Denied {}:{} by the target policy, it can't be resolved",
+                        target_host, target_port
+                    );
+
+                    return Err(ReplyError::ConnectionNotAllowed);
+                }
+
+                let stream = connect_direct(&direct_hosts, target_port, options.request_timeout)
+                    .await
+                    .map_err(map_proxy_connect_error)?;
+
+                debug!("Connected directly to {}:{}", target_host, target_port);
+
+                return Ok((None, stream));
+            }
+            RouteAction::Reject(err) => {
+                debug!("Rejected {}:{} by the rules", target_host, target_port);
+
+                return Err(err.clone());
+            }
+        },
+        None => options.pool_for(client.identity.as_ref()),
+    };
+    // A hostname the policy couldn't resolve may only go through the upstreams resolving it remotely
+    let excluded: Vec<usize> = match allowed_host {
+        AllowedHost::Unresolved => pool
+            .upstreams()
+            .enumerate()
+            .filter(|(_, upstream)| upstream.resolves_locally())
+            .map(|(index, _)| index)
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    if !pool.is_empty() && excluded.len() == pool.len() {
+        debug!(
+            "Denied {}:{} by the target policy, it can't be resolved",
+            target_host, target_port
+        );
+
+        return Err(ReplyError::ConnectionNotAllowed);
+    }
+
+    let (lease, stream) = connect_upstream(
+        pool,
+        &client.session(),
+        options,
+        &resolved_host,
+        &allowed_host,
+        excluded,
+        target_port,
+    )
+    .await
+    .map_err(map_proxy_connect_error)?;
+
+    match &client.identity {
+        Some(identity) => debug!("Connected to downstream proxy for {}", identity.username()),
+        None => debug!("Connected to downstream proxy"),
+    }
+
+    Ok((Some(lease), stream))
+}
+
+/// Connects directly to the checked addresses of the target, in turn until one of them accepts
+async fn connect_direct(
+    hosts: &[String],
+    target_port: u16,
+    timeout: Duration,
+) -> Result<ProxyStream, ProxyError> {
+    let connect = async {
+        let mut last_error = ProxyError::NoUpstream;
+
+        for host in hosts {
+            match Proxy::direct().connect(host, target_port).await {
+                Ok(stream) => return Ok(stream),
+                Err(err) => {
+                    debug!(
+                        "Can't connect directly to {}:{}: {}",
+                        host, target_port, err
+                    );
+
+                    last_error = err;
+                }
+            }
+        }
+
+        Err(last_error)
+    };
+
+    tokio::time::timeout(timeout, connect)
+        .await
+        .unwrap_or_else(|_| Err(ProxyError::ConnectionTimeout))
+}
+
+/// Connects to the target through the pool upstreams, failing over to the next upstream
+/// until the attempts or the deadline are exhausted.
+///
+/// The client gets no reply until this returns, so failed attempts are invisible to it.
+/// The `excluded` upstreams are never tried.
+async fn connect_upstream(
+    pool: &ProxyPool,
+    session: &SessionParams,
+    options: &RouterOptions,
+    target_host: &str,
+    allowed_host: &AllowedHost,
+    excluded: Vec<usize>,
+    target_port: u16,
+) -> Result<(PoolLease, ProxyStream), ProxyError> {
+    let started_at = Instant::now();
+    let max_attempts = options.connect_attempts.max(1);
+    let mut tried_upstreams = excluded;
+    let mut attempts = 0;
+    let mut last_error = ProxyError::NoUpstream;
+
+    while attempts < max_attempts {
+        let timeout = match options.connect_deadline {
+            Some(deadline) => match deadline.checked_sub(started_at.elapsed()) {
+                Some(remaining) if !remaining.is_zero() => options.request_timeout.min(remaining),
+                _ => break,
+            },
+            None => options.request_timeout,
+        };
+        let attempt_started_at = Instant::now();
+        let lease = match pool
+            .acquire_for_session(session, &tried_upstreams, timeout)
+            .await
+        {
+            Ok(lease) => lease,
+            Err(ProxyError::NoUpstream) => break,
+            Err(err) => {
+                last_error = err;
+                break;
+            }
+        };
+        // The time spent in the queue counts towards the attempt
+        let timeout = timeout.saturating_sub(attempt_started_at.elapsed());
+        // Rendered even without a session, so no placeholder is sent to the upstream
+        let upstream = lease.upstream().with_session(session);
+
+        tried_upstreams.push(lease.index());
+        attempts += 1;
+
+        // Upstreams resolving the target here connect to the addresses checked by the policy
+        let upstream_target_host = match upstream.resolves_locally() {
+            true => match allowed_host.local_host(target_host, upstream.resolves_ipv4_only()) {
+                Some(local_host) => local_host,
+                None => continue,
+            },
+            false => target_host.to_string(),
+        };
+
+        match upstream
+            .connect_with_timeout(&upstream_target_host, target_port, timeout)
+            .await
+        {
+            Ok(stream) => return Ok((lease, stream)),
+            Err(err) => {
+                warn!(
+                    "Upstream {} failed to connect to {}:{} (attempt {}/{}): {}",
+                    upstream, target_host, target_port, attempts, max_attempts, err
+                );
+
+                last_error = err;
+            }
+        }
+    }
+
+    Err(last_error)
+}
+
+fn map_proxy_connect_error(err: ProxyError) -> ReplyError {
+    let mut io_error: Option<std::io::Error> = None;
+
+    match err {
+        ProxyError::ConnectionTimeout => return ReplyError::ConnectionTimeout,
+        ProxyError::ResolveError(_) | ProxyError::UnresolvedHost(_) => {
+            return ReplyError::HostUnreachable;
+        }
+        ProxyError::UdpNotSupported(_) | ProxyError::BindNotSupported(_) => {
+            return ReplyError::CommandNotSupported;
+        }
+        // Errors replied by a socks5 upstream are passed to the client as is
+        ProxyError::SocksError(SocksError::ReplyError(err)) => return err,
+        ProxyError::HttpError(HttpError::IoError(err)) => {
+            io_error = Some(err);
+        }
+        ProxyError::TlsError(err) | ProxyError::ConnectError(err) => {
+            io_error = Some(err);
+        }
+        ProxyError::Socks4Error(Socks4Error::Io(err)) => {
+            io_error = Some(err);
+        }
+        ProxyError::Socks4Error(Socks4Error::UnsupportedAddress) => {
+            return ReplyError::AddressTypeNotSupported;
+        }
+        ProxyError::Socks4Error(Socks4Error::RequestRejected) => {
+            return ReplyError::ConnectionRefused;
+        }
+        ProxyError::Socks4Error(Socks4Error::IdentdUnreachable | Socks4Error::IdentdMismatch) => {
+            return ReplyError::ConnectionNotAllowed;
+        }
+        ProxyError::SocksError(SocksError::Io(err)) => {
+            io_error = Some(err);
+        }
+        _ => (),
+    };
+
+    if io_error.is_some() {
+        match io_error.unwrap().kind() {
+            ErrorKind::ConnectionRefused => {
+                return ReplyError::ConnectionRefused;
+            }
+            ErrorKind::ConnectionAborted => {
+                return ReplyError::ConnectionNotAllowed;
+            }
+            ErrorKind::ConnectionReset => {
+                return ReplyError::ConnectionNotAllowed;
+            }
+            ErrorKind::NotConnected => return ReplyError::NetworkUnreachable,
+            _ => (),
+        }
+    }
+
+    return ReplyError::GeneralFailure;
+}
+
+async fn transfer<S>(downstream: &mut ProxyStream, socket: &mut S) -> std::io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    debug!("Start data transfer");
+
+    match tokio::io::copy_bidirectional(downstream, socket).await {
+        Ok(res) => {
+            info!("Socket transfer finished ({}, {})", res.0, res.1);
+        }
+        Err(err) => match err.kind() {
+            ErrorKind::NotConnected => {
+                info!("Socket transfer closed by client");
+            }
+            ErrorKind::ConnectionReset => {
+                info!("Socket transfer closed by downstream proxy");
+            }
+            _ => return Err(err),
+        },
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::engine::general_purpose::STANDARD as BASE64;
+    use base64::Engine;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Accepts a single CONNECT request on the upstream and returns its head
+    async fn accept_connect(listener: TcpListener) -> String {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut head = Vec::new();
+        let mut buf = [0u8; 1024];
+
+        while !head.ends_with(b"\r\n\r\n") {
+            let len = stream.read(&mut buf).await.unwrap();
+
+            assert_ne!(len, 0, "CONNECT request not finished");
+            head.extend_from_slice(&buf[..len]);
+        }
+
+        stream
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+            .await
+            .unwrap();
+
+        String::from_utf8(head).unwrap()
+    }
+
+    async fn connect_with_session(session: SessionParams) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = format!(
+            "http://user-{{session}}-{{region}}:pass@{}",
+            listener.local_addr().unwrap()
+        );
+        let options = RouterOptionsBuilder::default()
+            .proxy(ProxyPool::from_urls([upstream]).unwrap())
+            .target_policy(TargetPolicy::allow_all())
+            .build()
+            .unwrap();
+        let client = Client {
+            local_addr: SocketAddr::from(([127, 0, 0, 1], 1080)),
+            peer_addr: SocketAddr::from(([127, 0, 0, 1], 50000)),
+            identity: Some(Identity::new("user").with_session(session)),
+        };
+        let (head, connected) = tokio::join!(
+            accept_connect(listener),
+            connect_target(&options, &client, "example.com".to_string(), 443)
+        );
+
+        assert!(connected.is_ok());
+
+        head
+    }
+
+    #[tokio::test]
+    async fn connect_renders_session_placeholders() {
+        let head = connect_with_session(
+            SessionParams::default()
+                .with_session("abc")
+                .with_region("us"),
+        )
+        .await;
+
+        assert!(
+            head.contains(&format!("Basic {}", BASE64.encode("user-abc-us:pass"))),
+            "{}",
+            head
+        );
+    }
+
+    #[tokio::test]
+    async fn connect_renders_placeholders_without_session() {
+        let head = connect_with_session(SessionParams::default()).await;
+
+        assert!(
+            head.contains(&format!("Basic {}", BASE64.encode("user--:pass"))),
+            "{}",
+            head
+        );
+    }
+}
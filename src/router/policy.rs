@@ -0,0 +1,373 @@
+use ipnet::IpNet;
+use std::net::IpAddr;
+use std::sync::OnceLock;
+use tokio::net::lookup_host;
+
+/// Networks denied by default: loopback, link-local (including the cloud metadata addresses),
+/// private, shared, unspecified, multicast and reserved ranges, and NAT64 which maps all of them
+const PRIVATE_NETWORKS: [&str; 16] = [
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/128",
+    "::1/128",
+    "64:ff9b::/96",
+    "fc00::/7",
+    "fe80::/10",
+    "fec0::/10",
+    "ff00::/8",
+];
+
+/// Decides which target addresses the clients may connect to.
+///
+/// The explicit allow list wins over the deny list, which wins over the private networks.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TargetPolicy {
+    deny_private: bool,
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+impl TargetPolicy {
+    /// Denies the private networks
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allows every target, unless it's added to the deny list
+    pub fn allow_all() -> Self {
+        Self {
+            deny_private: false,
+            allow: Vec::new(),
+            deny: Vec::new(),
+        }
+    }
+
+    pub fn allow(mut self, net: impl Into<IpNet>) -> Self {
+        self.allow.push(net.into());
+        self
+    }
+
+    pub fn deny(mut self, net: impl Into<IpNet>) -> Self {
+        self.deny.push(net.into());
+        self
+    }
+
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        let ip = canonical_ip(ip);
+
+        if self.allow.iter().any(|net| net.contains(&ip)) {
+            return true;
+        }
+
+        if self.deny.iter().any(|net| net.contains(&ip)) {
+            return false;
+        }
+
+        !(self.deny_private && is_private(ip))
+    }
+
+    /// Checks the target host, `None` if it's denied.
+    ///
+    /// Hostnames are resolved and all of their addresses must be allowed. The connections resolving the target
+    /// on this host must use the checked addresses, otherwise another lookup could return a denied one.
+    pub(crate) async fn check_host(&self, host: &str, port: u16) -> Option<AllowedHost> {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return self.is_allowed(ip).then(|| AllowedHost::Addrs(vec![ip]));
+        }
+
+        if !self.deny_private && self.deny.is_empty() {
+            return Some(AllowedHost::Any);
+        }
+
+        let addrs: Vec<IpAddr> = match lookup_host((host, port)).await {
+            Ok(addrs) => addrs.map(|addr| addr.ip()).collect(),
+            Err(_) => return Some(AllowedHost::Unresolved),
+        };
+
+        if addrs.is_empty() {
+            return Some(AllowedHost::Unresolved);
+        }
+
+        if !addrs.iter().all(|ip| self.is_allowed(*ip)) {
+            return None;
+        }
+
+        Some(AllowedHost::Addrs(addrs))
+    }
+}
+
+/// A target host allowed by the policy
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum AllowedHost {
+    /// The policy denies nothing, so the host isn't checked
+    Any,
+    /// The checked addresses of the host
+    Addrs(Vec<IpAddr>),
+    /// The hostname can't be resolved here, so only the upstreams resolving it remotely may connect
+    Unresolved,
+}
+
+impl AllowedHost {
+    /// The hosts to connect to when the target is resolved on this host, in the order of the lookup.
+    ///
+    /// Empty if the target must not be resolved here, or if it has no address of the required family.
+    pub(crate) fn local_hosts(&self, host: &str, ipv4_only: bool) -> Vec<String> {
+        match self {
+            Self::Any => vec![host.to_string()],
+            Self::Addrs(addrs) => addrs
+                .iter()
+                .filter(|ip| !ipv4_only || ip.is_ipv4())
+                .map(IpAddr::to_string)
+                .collect(),
+            Self::Unresolved => Vec::new(),
+        }
+    }
+
+    /// The first of the local hosts, `None` if there is none
+    pub(crate) fn local_host(&self, host: &str, ipv4_only: bool) -> Option<String> {
+        self.local_hosts(host, ipv4_only).into_iter().next()
+    }
+}
+
+impl Default for TargetPolicy {
+    fn default() -> Self {
+        Self {
+            deny_private: true,
+            allow: Vec::new(),
+            deny: Vec::new(),
+        }
+    }
+}
+
+/// Decides which client addresses are accepted by the router.
+///
+/// When the allow list isn't empty only the clients in it are accepted, the deny list wins over the allow list.
+/// Unix socket clients have the `127.0.0.1` address.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClientPolicy {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+impl ClientPolicy {
+    /// Accepts every client
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn allow(mut self, net: impl Into<IpNet>) -> Self {
+        self.allow.push(net.into());
+        self
+    }
+
+    pub fn deny(mut self, net: impl Into<IpNet>) -> Self {
+        self.deny.push(net.into());
+        self
+    }
+
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        let ip = canonical_ip(ip);
+
+        if self.deny.iter().any(|net| net.contains(&ip)) {
+            return false;
+        }
+
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip))
+    }
+}
+
+/// IPv4 addresses mapped to IPv6 are checked as IPv4, so they can't bypass the lists
+fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ip) => ip
+            .to_ipv4_mapped()
+            .map(IpAddr::V4)
+            .unwrap_or(IpAddr::V6(ip)),
+        ip => ip,
+    }
+}
+
+fn is_private(ip: IpAddr) -> bool {
+    static NETWORKS: OnceLock<Vec<IpNet>> = OnceLock::new();
+
+    NETWORKS
+        .get_or_init(|| {
+            PRIVATE_NETWORKS
+                .iter()
+                .map(|net| net.parse().expect("invalid private network"))
+                .collect()
+        })
+        .iter()
+        .any(|net| net.contains(&ip))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn net(net: &str) -> IpNet {
+        net.parse().unwrap()
+    }
+
+    #[test]
+    fn canonical_ip_unmaps_ipv4() {
+        assert_eq!(canonical_ip(ip("::ffff:127.0.0.1")), ip("127.0.0.1"));
+        assert_eq!(canonical_ip(ip("::ffff:10.0.0.1")), ip("10.0.0.1"));
+        assert_eq!(canonical_ip(ip("::1")), ip("::1"));
+        assert_eq!(canonical_ip(ip("2001:db8::1")), ip("2001:db8::1"));
+        assert_eq!(canonical_ip(ip("192.0.2.1")), ip("192.0.2.1"));
+    }
+
+    #[test]
+    fn target_policy_denies_private_by_default() {
+        let policy = TargetPolicy::default();
+
+        for denied in [
+            "0.0.0.0",
+            "10.1.2.3",
+            "100.64.0.1",
+            "127.0.0.1",
+            "169.254.169.254",
+            "172.16.0.1",
+            "192.168.1.1",
+            "224.0.0.1",
+            "239.255.255.250",
+            "240.0.0.1",
+            "255.255.255.255",
+            "::",
+            "::1",
+            "64:ff9b::7f00:1",
+            "64:ff9b::a9fe:a9fe",
+            "fd00:ec2::254",
+            "fe80::1",
+            "ff02::1",
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+        ] {
+            assert!(!policy.is_allowed(ip(denied)), "{} must be denied", denied);
+        }
+
+        for allowed in ["1.1.1.1", "8.8.8.8", "2606:4700::1111", "::ffff:1.1.1.1"] {
+            assert!(
+                policy.is_allowed(ip(allowed)),
+                "{} must be allowed",
+                allowed
+            );
+        }
+    }
+
+    #[test]
+    fn target_policy_allow_wins_over_deny() {
+        let policy = TargetPolicy::new()
+            .allow(net("10.1.0.0/16"))
+            .deny(net("10.1.2.0/24"))
+            .deny(net("203.0.113.0/24"));
+
+        assert!(policy.is_allowed(ip("10.1.2.3")));
+        assert!(policy.is_allowed(ip("::ffff:10.1.2.3")));
+        assert!(!policy.is_allowed(ip("10.2.0.1")));
+        assert!(!policy.is_allowed(ip("203.0.113.1")));
+        assert!(!policy.is_allowed(ip("::ffff:203.0.113.1")));
+        assert!(policy.is_allowed(ip("198.51.100.1")));
+    }
+
+    #[test]
+    fn target_policy_allow_all_keeps_deny_list() {
+        let policy = TargetPolicy::allow_all().deny(net("169.254.169.254/32"));
+
+        assert!(policy.is_allowed(ip("127.0.0.1")));
+        assert!(policy.is_allowed(ip("10.0.0.1")));
+        assert!(!policy.is_allowed(ip("169.254.169.254")));
+        assert!(!policy.is_allowed(ip("::ffff:169.254.169.254")));
+    }
+
+    #[tokio::test]
+    async fn target_policy_checks_hosts() {
+        let policy = TargetPolicy::default();
+
+        assert_eq!(policy.check_host("127.0.0.1", 80).await, None);
+        assert_eq!(policy.check_host("localhost", 80).await, None);
+        assert_eq!(
+            policy.check_host("1.1.1.1", 80).await,
+            Some(AllowedHost::Addrs(vec![ip("1.1.1.1")]))
+        );
+        assert_eq!(
+            policy.check_host("host.invalid", 80).await,
+            Some(AllowedHost::Unresolved)
+        );
+        assert_eq!(
+            TargetPolicy::allow_all().check_host("localhost", 80).await,
+            Some(AllowedHost::Any)
+        );
+    }
+
+    #[test]
+    fn allowed_host_pins_local_connections() {
+        let addrs = AllowedHost::Addrs(vec![ip("2001:db8::1"), ip("192.0.2.1"), ip("192.0.2.2")]);
+
+        assert_eq!(
+            AllowedHost::Any.local_hosts("example.com", false),
+            ["example.com"]
+        );
+        assert_eq!(
+            AllowedHost::Any.local_host("example.com", true),
+            Some("example.com".to_string())
+        );
+        assert_eq!(
+            addrs.local_hosts("example.com", false),
+            ["2001:db8::1", "192.0.2.1", "192.0.2.2"]
+        );
+        assert_eq!(
+            addrs.local_host("example.com", true),
+            Some("192.0.2.1".to_string())
+        );
+        assert_eq!(
+            AllowedHost::Addrs(vec![ip("2001:db8::1")]).local_host("example.com", true),
+            None
+        );
+        assert!(AllowedHost::Unresolved
+            .local_hosts("example.com", false)
+            .is_empty());
+    }
+
+    #[test]
+    fn client_policy_accepts_all_by_default() {
+        let policy = ClientPolicy::new();
+
+        assert!(policy.is_allowed(ip("127.0.0.1")));
+        assert!(policy.is_allowed(ip("203.0.113.1")));
+        assert!(policy.is_allowed(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn client_policy_deny_wins_over_allow() {
+        let policy = ClientPolicy::new()
+            .allow(net("10.0.0.0/8"))
+            .deny(net("10.0.5.0/24"));
+
+        assert!(policy.is_allowed(ip("10.1.1.1")));
+        assert!(policy.is_allowed(ip("::ffff:10.1.1.1")));
+        assert!(!policy.is_allowed(ip("10.0.5.1")));
+        assert!(!policy.is_allowed(ip("::ffff:10.0.5.1")));
+        assert!(!policy.is_allowed(ip("192.168.1.1")));
+    }
+
+    #[test]
+    fn client_policy_deny_only() {
+        let policy = ClientPolicy::new().deny(net("203.0.113.0/24"));
+
+        assert!(!policy.is_allowed(ip("203.0.113.7")));
+        assert!(policy.is_allowed(ip("198.51.100.7")));
+    }
+}
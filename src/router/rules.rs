@@ -0,0 +1,294 @@
+use crate::proxy::ProxyPool;
+use fast_socks5::ReplyError;
+use ipnet::IpNet;
+use regex::Regex;
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+
+/// Condition on the target of a request
+#[derive(Debug, Clone)]
+pub enum TargetMatcher {
+    /// Exact hostname, case-insensitive
+    Domain(String),
+    /// The domain and all of its subdomains
+    DomainSuffix(String),
+    /// Hostname pattern where `*` stands for a single label, e.g. `*.example.com`
+    DomainWildcard(String),
+    DomainRegex(Regex),
+    /// Target IP, domain targets match only if they're resolved locally
+    Cidr(IpNet),
+    Ports(RangeInclusive<u16>),
+}
+
+impl TargetMatcher {
+    pub fn domain(domain: impl AsRef<str>) -> Self {
+        Self::Domain(normalize_domain(domain.as_ref()))
+    }
+
+    pub fn domain_suffix(suffix: impl AsRef<str>) -> Self {
+        Self::DomainSuffix(normalize_domain(suffix.as_ref().trim_start_matches('.')))
+    }
+
+    pub fn domain_wildcard(pattern: impl AsRef<str>) -> Self {
+        Self::DomainWildcard(normalize_domain(pattern.as_ref()))
+    }
+
+    pub fn domain_regex(pattern: &str) -> Result<Self, regex::Error> {
+        Ok(Self::DomainRegex(Regex::new(pattern)?))
+    }
+
+    /// Accepts `10.0.0.0/8` like networks as well as single addresses
+    pub fn cidr(cidr: &str) -> Result<Self, ipnet::AddrParseError> {
+        match cidr.parse::<IpAddr>() {
+            Ok(ip) => Ok(Self::Cidr(IpNet::from(ip))),
+            Err(_) => Ok(Self::Cidr(cidr.parse()?)),
+        }
+    }
+
+    pub fn ports(ports: RangeInclusive<u16>) -> Self {
+        Self::Ports(ports)
+    }
+
+    pub(crate) fn matches(&self, target: &Target<'_>) -> bool {
+        match self {
+            Self::Domain(domain) => target.domain().is_some_and(|host| host == *domain),
+            Self::DomainSuffix(suffix) => target.domain().is_some_and(|host| {
+                host == *suffix
+                    || host
+                        .strip_suffix(suffix.as_str())
+                        .is_some_and(|prefix| prefix.ends_with('.'))
+            }),
+            Self::DomainWildcard(pattern) => target.domain().is_some_and(|host| {
+                let mut labels = host.split('.');
+                let mut pattern_labels = pattern.split('.');
+
+                loop {
+                    match (labels.next(), pattern_labels.next()) {
+                        (None, None) => return true,
+                        (Some(label), Some(pattern_label)) => {
+                            if pattern_label != "*" && pattern_label != label {
+                                return false;
+                            }
+                        }
+                        _ => return false,
+                    }
+                }
+            }),
+            Self::DomainRegex(regex) => target.domain().is_some_and(|host| regex.is_match(&host)),
+            Self::Cidr(net) => target.ip.is_some_and(|ip| net.contains(&ip)),
+            Self::Ports(ports) => ports.contains(&target.port),
+        }
+    }
+}
+
+/// What happens to the requests matching a rule
+#[derive(Debug, Clone)]
+pub enum RouteAction {
+    /// Tunnels through the upstream, which may be a single proxy, a chain or a pool
+    Upstream(ProxyPool),
+    /// Connects to the target without a proxy, same as an upstream of `Proxy::direct()`
+    Direct,
+    Reject(ReplyError),
+}
+
+impl RouteAction {
+    pub fn upstream(upstream: impl Into<ProxyPool>) -> Self {
+        Self::Upstream(upstream.into())
+    }
+}
+
+/// Routing rule, matching when all of its matchers match the target.
+///
+/// A rule without matchers matches every target.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    matchers: Vec<TargetMatcher>,
+    action: RouteAction,
+}
+
+impl Rule {
+    pub fn new(action: RouteAction) -> Self {
+        Self {
+            matchers: Vec::new(),
+            action,
+        }
+    }
+
+    pub fn with(mut self, matcher: TargetMatcher) -> Self {
+        self.matchers.push(matcher);
+        self
+    }
+
+    pub fn action(&self) -> &RouteAction {
+        &self.action
+    }
+
+    pub(crate) fn action_mut(&mut self) -> &mut RouteAction {
+        &mut self.action
+    }
+
+    pub(crate) fn matches(&self, target: &Target<'_>) -> bool {
+        self.matchers.iter().all(|matcher| matcher.matches(target))
+    }
+
+    /// Whether the rule has no matchers, so it matches every target
+    pub(crate) fn matches_all(&self) -> bool {
+        self.matchers.is_empty()
+    }
+}
+
+/// The target of a request as seen by the rules
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Target<'a> {
+    host: &'a str,
+    ip: Option<IpAddr>,
+    port: u16,
+}
+
+impl<'a> Target<'a> {
+    /// `ip` is the address of the host, if it's an IP or was resolved locally
+    pub(crate) fn new(host: &'a str, ip: Option<IpAddr>, port: u16) -> Self {
+        Self { host, ip, port }
+    }
+
+    /// The normalized hostname, if the target isn't an IP
+    fn domain(&self) -> Option<String> {
+        match self.host.parse::<IpAddr>() {
+            Ok(_) => None,
+            Err(_) => Some(normalize_domain(self.host)),
+        }
+    }
+}
+
+fn normalize_domain(domain: &str) -> String {
+    domain.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn domain(host: &str) -> Target<'_> {
+        Target::new(host, None, 443)
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn domain_is_normalized() {
+        let matcher = TargetMatcher::domain("Example.COM.");
+
+        assert!(matcher.matches(&domain("example.com")));
+        assert!(matcher.matches(&domain("EXAMPLE.com.")));
+        assert!(!matcher.matches(&domain("www.example.com")));
+        assert!(!matcher.matches(&domain("example.org")));
+    }
+
+    #[test]
+    fn domain_suffix_matches_subdomains() {
+        let matcher = TargetMatcher::domain_suffix(".Example.com.");
+
+        assert!(matcher.matches(&domain("example.com")));
+        assert!(matcher.matches(&domain("www.example.com")));
+        assert!(matcher.matches(&domain("a.b.EXAMPLE.com.")));
+        // The suffix must start at a label boundary
+        assert!(!matcher.matches(&domain("badexample.com")));
+        assert!(!matcher.matches(&domain("example.com.evil")));
+        assert!(!matcher.matches(&domain("com")));
+    }
+
+    #[test]
+    fn domain_wildcard_matches_single_labels() {
+        let matcher = TargetMatcher::domain_wildcard("*.Example.com");
+
+        assert!(matcher.matches(&domain("www.example.com")));
+        assert!(matcher.matches(&domain("API.example.com.")));
+        assert!(!matcher.matches(&domain("example.com")));
+        assert!(!matcher.matches(&domain("a.b.example.com")));
+        assert!(!matcher.matches(&domain("www.example.org")));
+
+        let matcher = TargetMatcher::domain_wildcard("api.*.example.com");
+
+        assert!(matcher.matches(&domain("api.eu.example.com")));
+        assert!(!matcher.matches(&domain("www.eu.example.com")));
+        assert!(!matcher.matches(&domain("api.example.com")));
+    }
+
+    #[test]
+    fn domain_matchers_skip_ip_targets() {
+        let target = Target::new("192.0.2.1", Some(ip("192.0.2.1")), 443);
+
+        assert!(!TargetMatcher::domain_regex(".*").unwrap().matches(&target));
+        assert!(!TargetMatcher::domain_suffix("1").matches(&target));
+        assert!(!TargetMatcher::domain_wildcard("*.*.*.*").matches(&target));
+    }
+
+    #[test]
+    fn cidr_matches_resolved_targets() {
+        let matcher = TargetMatcher::cidr("10.0.0.0/8").unwrap();
+
+        assert!(matcher.matches(&Target::new("10.1.2.3", Some(ip("10.1.2.3")), 443)));
+        assert!(matcher.matches(&Target::new("internal.example", Some(ip("10.1.2.3")), 443)));
+        assert!(!matcher.matches(&Target::new("internal.example", None, 443)));
+        assert!(!matcher.matches(&Target::new("192.0.2.1", Some(ip("192.0.2.1")), 443)));
+
+        let matcher = TargetMatcher::cidr("2001:db8::1").unwrap();
+
+        assert!(matcher.matches(&Target::new("2001:db8::1", Some(ip("2001:db8::1")), 443)));
+        assert!(!matcher.matches(&Target::new("2001:db8::2", Some(ip("2001:db8::2")), 443)));
+    }
+
+    #[test]
+    fn ports_match_the_range() {
+        let matcher = TargetMatcher::ports(8000..=8080);
+
+        assert!(matcher.matches(&Target::new("example.com", None, 8000)));
+        assert!(matcher.matches(&Target::new("example.com", None, 8080)));
+        assert!(!matcher.matches(&Target::new("example.com", None, 443)));
+    }
+
+    #[test]
+    fn rule_matches_when_all_matchers_match() {
+        let rule = Rule::new(RouteAction::Direct)
+            .with(TargetMatcher::domain_suffix("example.com"))
+            .with(TargetMatcher::ports(443..=443));
+
+        assert!(rule.matches(&Target::new("www.example.com", None, 443)));
+        assert!(!rule.matches(&Target::new("www.example.com", None, 80)));
+        assert!(!rule.matches(&Target::new("www.example.org", None, 443)));
+        assert!(!rule.matches_all());
+        assert!(Rule::new(RouteAction::Direct).matches(&domain("example.com")));
+        assert!(Rule::new(RouteAction::Direct).matches_all());
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let rules = [
+            Rule::new(RouteAction::Direct).with(TargetMatcher::domain("safe.example.com")),
+            Rule::new(RouteAction::Reject(ReplyError::ConnectionNotAllowed))
+                .with(TargetMatcher::domain_suffix("example.com")),
+            Rule::new(RouteAction::Reject(ReplyError::HostUnreachable)),
+        ];
+        let action = |host: &str| {
+            rules
+                .iter()
+                .find(|rule| rule.matches(&domain(host)))
+                .map(Rule::action)
+        };
+
+        assert!(matches!(
+            action("safe.example.com"),
+            Some(RouteAction::Direct)
+        ));
+        assert!(matches!(
+            action("www.example.com"),
+            Some(RouteAction::Reject(ReplyError::ConnectionNotAllowed))
+        ));
+        assert!(matches!(
+            action("example.org"),
+            Some(RouteAction::Reject(ReplyError::HostUnreachable))
+        ));
+    }
+}
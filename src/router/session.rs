@@ -0,0 +1,159 @@
+use crate::proxy::{Rotation, SessionParams};
+use derive_builder::Builder;
+
+/// Grammar of the session parameters appended to the client username,
+/// e.g. `user-session-abc123-country-de` with the default keys
+#[derive(Debug, Clone, PartialEq, Builder)]
+pub struct UsernameGrammar {
+    #[builder(default = "'-'")]
+    separator: char,
+    #[builder(setter(into), default = "\"session\".to_string()")]
+    session_key: String,
+    #[builder(setter(into), default = "\"country\".to_string()")]
+    region_key: String,
+    /// Accepts the `sticky` and `rotate` values
+    #[builder(setter(into), default = "\"rotation\".to_string()")]
+    rotation_key: String,
+}
+
+impl UsernameGrammar {
+    pub fn builder() -> UsernameGrammarBuilder {
+        UsernameGrammarBuilder::default()
+    }
+
+    /// Splits the username into the base username and the session parameters.
+    ///
+    /// The parameters start at the first known key, unknown keys after it are ignored.
+    pub fn parse<'a>(&self, username: &'a str) -> (&'a str, SessionParams) {
+        let mut params = SessionParams::default();
+        let tokens: Vec<&str> = username.split(self.separator).collect();
+        let start = match (1..tokens.len()).find(|&i| self.is_key(tokens[i])) {
+            Some(start) => start,
+            None => return (username, params),
+        };
+        // The base username is the part before the separator of the first key
+        let base_len = tokens[..start].iter().map(|t| t.len()).sum::<usize>()
+            + (start - 1) * self.separator.len_utf8();
+
+        for pair in tokens[start..].chunks(2) {
+            let (key, value) = match pair {
+                [key, value] if !value.is_empty() => (*key, *value),
+                _ => continue,
+            };
+
+            if key == self.session_key {
+                params = params.with_session(value);
+            } else if key == self.region_key {
+                params = params.with_region(value);
+            } else if key == self.rotation_key {
+                match value {
+                    "sticky" => params = params.with_rotation(Rotation::Sticky),
+                    "rotate" => params = params.with_rotation(Rotation::PerConnection),
+                    _ => (),
+                }
+            }
+        }
+
+        (&username[..base_len], params)
+    }
+
+    fn is_key(&self, token: &str) -> bool {
+        token == self.session_key || token == self.region_key || token == self.rotation_key
+    }
+}
+
+impl Default for UsernameGrammar {
+    fn default() -> Self {
+        Self {
+            separator: '-',
+            session_key: "session".to_string(),
+            region_key: "country".to_string(),
+            rotation_key: "rotation".to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_without_keys() {
+        let grammar = UsernameGrammar::default();
+
+        assert_eq!(grammar.parse("alice"), ("alice", SessionParams::default()));
+        assert_eq!(
+            grammar.parse("alice-team-1"),
+            ("alice-team-1", SessionParams::default())
+        );
+        assert_eq!(grammar.parse(""), ("", SessionParams::default()));
+    }
+
+    #[test]
+    fn parse_key_as_base_username() {
+        let grammar = UsernameGrammar::default();
+
+        assert_eq!(
+            grammar.parse("session-abc"),
+            ("session-abc", SessionParams::default())
+        );
+        assert_eq!(
+            grammar.parse("country-session-abc"),
+            ("country", SessionParams::new().with_session("abc"))
+        );
+    }
+
+    #[test]
+    fn parse_missing_value() {
+        let grammar = UsernameGrammar::default();
+
+        assert_eq!(
+            grammar.parse("alice-session"),
+            ("alice", SessionParams::default())
+        );
+        assert_eq!(
+            grammar.parse("alice-session--country-de"),
+            ("alice", SessionParams::new().with_region("de"))
+        );
+    }
+
+    #[test]
+    fn parse_ignores_unknown_keys_after_first_key() {
+        let grammar = UsernameGrammar::default();
+
+        assert_eq!(
+            grammar.parse("alice-team-x-session-abc-foo-bar-country-de-rotation-sticky"),
+            (
+                "alice-team-x",
+                SessionParams::new()
+                    .with_session("abc")
+                    .with_region("de")
+                    .with_rotation(Rotation::Sticky)
+            )
+        );
+        assert_eq!(
+            grammar.parse("alice-rotation-unknown"),
+            ("alice", SessionParams::default())
+        );
+    }
+
+    #[test]
+    fn parse_multi_byte_separator() {
+        let grammar = UsernameGrammar::builder().separator('·').build().unwrap();
+
+        assert_eq!(
+            grammar.parse("alice·dev·session·abc·country·de"),
+            (
+                "alice·dev",
+                SessionParams::new().with_session("abc").with_region("de")
+            )
+        );
+        assert_eq!(
+            grammar.parse("älice·rotation·rotate"),
+            (
+                "älice",
+                SessionParams::new().with_rotation(Rotation::PerConnection)
+            )
+        );
+    }
+}
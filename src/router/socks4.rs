@@ -0,0 +1,175 @@
+use super::handle::spawn_router;
+use super::listener::RouterStream;
+use super::{connect_target, transfer, Client, Listener, RouterHandle};
+use crate::router::auth::Identity;
+use anyhow::anyhow;
+use fast_socks5::util::target_addr::TargetAddr;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+pub use super::{RouterOptions, RouterOptionsBuilder};
+
+const SOCKS4_VERSION: u8 = 4;
+const SOCKS4_COMMAND_CONNECT: u8 = 1;
+const SOCKS4_REPLY_GRANTED: u8 = 90;
+const SOCKS4_REPLY_REJECTED: u8 = 91;
+const SOCKS4_REPLY_USER_MISMATCH: u8 = 93;
+/// Limit of the user-ID and of the socks4a hostname
+const MAX_FIELD_SIZE: u64 = 255;
+
+/// Spawns a socks4/4a router, the user-ID of the clients is used as the username for routing
+pub async fn spawn_socks4_router(options: RouterOptions) -> anyhow::Result<RouterHandle> {
+    let listeners = options.bind_listeners()?;
+
+    Ok(spawn_socks4_router_with_listeners(listeners, options))
+}
+
+/// Serves the connections of the given listeners instead of binding the listen addresses of the options
+pub fn spawn_socks4_router_with_listeners(
+    listeners: Vec<Listener>,
+    options: RouterOptions,
+) -> RouterHandle {
+    spawn_router(listeners, options, handle_socket)
+}
+
+pub(super) async fn handle_socket(
+    stream: RouterStream,
+    options: Arc<RouterOptions>,
+) -> anyhow::Result<()> {
+    let local_addr = stream.local_addr();
+    let peer_addr = stream.peer_addr();
+    let mut socket = BufReader::new(stream);
+    let request =
+        match tokio::time::timeout(options.request_timeout, read_request(&mut socket)).await {
+            Ok(Ok(request)) => request,
+            Ok(Err(err)) => {
+                write_reply(&mut socket, SOCKS4_REPLY_REJECTED, None).await?;
+
+                return Err(err);
+            }
+            Err(_) => {
+                write_reply(&mut socket, SOCKS4_REPLY_REJECTED, None).await?;
+
+                return Err(anyhow!("Request timeout"));
+            }
+        };
+
+    // Socks4 has no password, so the clients can't pass the authentication
+    if options.authenticator.is_some() {
+        write_reply(&mut socket, SOCKS4_REPLY_USER_MISMATCH, None).await?;
+
+        return Err(anyhow!(
+            "Socks4 client rejected, authentication is required"
+        ));
+    }
+
+    if request.command != SOCKS4_COMMAND_CONNECT {
+        write_reply(&mut socket, SOCKS4_REPLY_REJECTED, None).await?;
+
+        return Err(anyhow!("Unsupported socks4 command {}", request.command));
+    }
+
+    let client = Client {
+        local_addr,
+        peer_addr,
+        identity: match request.user_id.is_empty() {
+            true => None,
+            false => Some(Identity::from_username(
+                &request.user_id,
+                options.username_grammar.as_ref(),
+            )),
+        },
+    };
+    let (_lease, mut downstream) =
+        match connect_target(&options, &client, request.target_host, request.target_port).await {
+            Ok(res) => res,
+            Err(err) => {
+                write_reply(&mut socket, SOCKS4_REPLY_REJECTED, None).await?;
+
+                return Err(anyhow!("{}", err));
+            }
+        };
+
+    write_reply(&mut socket, SOCKS4_REPLY_GRANTED, downstream.bound_addr()).await?;
+
+    Ok(transfer(&mut downstream, &mut socket).await?)
+}
+
+#[derive(Debug)]
+struct Request {
+    command: u8,
+    target_host: String,
+    target_port: u16,
+    user_id: String,
+}
+
+async fn read_request<R>(reader: &mut R) -> anyhow::Result<Request>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut header = [0u8; 8];
+
+    reader.read_exact(&mut header).await?;
+
+    if header[0] != SOCKS4_VERSION {
+        return Err(anyhow!("Invalid socks4 version {}", header[0]));
+    }
+
+    let target_port = u16::from_be_bytes([header[2], header[3]]);
+    let target_ip = Ipv4Addr::new(header[4], header[5], header[6], header[7]);
+    let user_id = read_field(reader).await?;
+    // Socks4a sends 0.0.0.x (x != 0) followed by the hostname
+    let target_host = match target_ip.octets() {
+        [0, 0, 0, last] if last != 0 => read_field(reader).await?,
+        _ => target_ip.to_string(),
+    };
+
+    Ok(Request {
+        command: header[1],
+        target_host,
+        target_port,
+        user_id,
+    })
+}
+
+/// Reads a null-terminated string
+async fn read_field<R>(reader: &mut R) -> anyhow::Result<String>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut field = Vec::new();
+
+    (&mut *reader)
+        .take(MAX_FIELD_SIZE + 1)
+        .read_until(0, &mut field)
+        .await?;
+
+    if field.pop() != Some(0) {
+        return Err(anyhow!("Socks4 request field is too long or incomplete"));
+    }
+
+    Ok(String::from_utf8(field)?)
+}
+
+/// Replies with the bound address if it's an IPv4 one, socks4 can't carry other addresses
+async fn write_reply(
+    socket: &mut BufReader<RouterStream>,
+    code: u8,
+    bound_addr: Option<&TargetAddr>,
+) -> std::io::Result<()> {
+    let (ip, port) = match bound_addr {
+        Some(TargetAddr::Ip(SocketAddr::V4(addr))) => (*addr.ip(), addr.port()),
+        _ => (Ipv4Addr::UNSPECIFIED, 0),
+    };
+    let mut reply = vec![
+        0,    // reply version
+        code, // reply code
+    ];
+
+    reply.extend_from_slice(&port.to_be_bytes());
+    reply.extend_from_slice(&ip.octets());
+
+    socket.write_all(&reply).await?;
+    socket.flush().await
+}
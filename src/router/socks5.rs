@@ -1,85 +1,76 @@
-use crate::proxy::{Proxy, ProxyError};
+use super::handle::spawn_router;
+use super::listener::RouterStream;
+use super::{
+    connect_target, map_proxy_connect_error, rejected_by_rules, resolve_target, transfer, Client,
+    Listener, RouterHandle,
+};
+use crate::proxy::socks5::{
+    decode_udp_datagram, encode_addr, encode_udp_datagram, target_addr as socks5_target_addr,
+    MAX_DATAGRAM_SIZE,
+};
+use crate::proxy::{resolve_host, DnsResolution};
+use crate::router::auth::RouterAuthentication;
+use crate::router::policy::AllowedHost;
 use anyhow::Context;
-use async_http_proxy::HttpError;
-use derive_builder::Builder;
-use fast_socks5::server::{Config as Socks5Config, DenyAuthentication, Socks5Server, Socks5Socket};
+use fast_socks5::server::{Config as Socks5Config, Socks5Socket};
+use fast_socks5::util::target_addr::TargetAddr;
 use fast_socks5::{ReplyError, Socks5Command, SocksError};
 use log::{debug, error, info};
-use std::io::ErrorKind;
-use std::net::ToSocketAddrs;
-use std::time::Duration;
-use tokio::io::AsyncWriteExt;
-use tokio::net::TcpStream;
-use tokio::task;
-use tokio_stream::StreamExt;
-
-#[derive(Debug, Clone, Default, PartialEq, Builder)]
-#[builder(setter(strip_option))]
-pub struct RouterOptions {
-    proxy: Proxy,
-    listen_port: u16,
-    #[builder(setter(into), default)]
-    listen_host: Option<String>,
-    #[builder(default = "Duration::from_secs(10)")]
-    request_timeout: Duration,
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UdpSocket;
+
+pub use super::{RouterOptions, RouterOptionsBuilder};
+
+pub async fn spawn_socks5_router(options: RouterOptions) -> anyhow::Result<RouterHandle> {
+    let listeners = options.bind_listeners()?;
+
+    Ok(spawn_socks5_router_with_listeners(listeners, options))
 }
 
-impl RouterOptions {
-    pub fn builder() -> RouterOptionsBuilder {
-        RouterOptionsBuilder::default()
-    }
+/// Serves the connections of the given listeners instead of binding the listen addresses of the options
+pub fn spawn_socks5_router_with_listeners(
+    listeners: Vec<Listener>,
+    options: RouterOptions,
+) -> RouterHandle {
+    spawn_router(listeners, options, |stream, options| async move {
+        handle_socket(stream, options)
+            .await
+            .map_err(anyhow::Error::from)
+    })
 }
 
-pub async fn spawn_socks5_router(options: RouterOptions) -> anyhow::Result<task::JoinHandle<()>> {
-    let listen_addr = [
-        options.listen_host.unwrap_or("127.0.0.1".to_string()),
-        options.listen_port.to_string(),
-    ]
-    .join(":");
-    let mut server_config = <Socks5Config>::default();
+fn server_config(options: &RouterOptions) -> Socks5Config<RouterAuthentication> {
+    let mut server_config = Socks5Config::<RouterAuthentication>::default();
 
     server_config.set_execute_command(false);
+    // Targets are resolved by `resolve_target`, so the domains reach the rules and the upstream
+    server_config.set_dns_resolve(false);
     server_config.set_request_timeout(options.request_timeout.as_secs());
 
-    let listener = <Socks5Server>::bind(&listen_addr)
-        .await
-        .context(format!("Can't bind the socks5 server to {}", listen_addr))?
-        .with_config(server_config);
-
-    let join_handle = task::spawn(async move {
-        let mut incoming = listener.incoming();
-
-        while let Some(socket_res) = incoming.next().await {
-            match socket_res {
-                Ok(socket) => {
-                    let proxy = options.proxy.clone();
-
-                    task::spawn(async move {
-                        if let Err(err) =
-                            handle_socket(socket, proxy, options.request_timeout).await
-                        {
-                            error!("Socket handle error: {:#}", err);
-                        }
-                    });
-                }
-                Err(err) => {
-                    error!("Socket accept error: {:#}", err);
-                }
-            }
-        }
-    });
+    if let Some(authentication) = options.authentication() {
+        server_config = server_config.with_authentication(authentication);
+    }
 
-    Ok(join_handle)
+    server_config
 }
 
-async fn handle_socket(
-    socket: Socks5Socket<TcpStream, DenyAuthentication>,
-    proxy: Proxy,
-    timeout: Duration,
+pub(super) async fn handle_socket(
+    stream: RouterStream,
+    options: Arc<RouterOptions>,
 ) -> Result<(), SocksError> {
+    let local_addr = stream.local_addr();
+    let peer_addr = stream.peer_addr();
+    let socket = Socks5Socket::new(stream, Arc::new(server_config(&options)));
     let mut socks5_socket = socket.upgrade_to_socks5().await?;
+    let client = Client {
+        local_addr,
+        peer_addr,
+        identity: socks5_socket.get_credentials().cloned(),
+    };
 
-    match execute_command(&mut socks5_socket, proxy, timeout).await {
+    match execute_command(&mut socks5_socket, &options, &client).await {
         Ok(_) => (),
         Err(SocksError::ReplyError(err)) => {
             // If a reply error has been returned, we send it to the client
@@ -94,104 +85,290 @@ async fn handle_socket(
 }
 
 async fn execute_command(
-    socket: &mut Socks5Socket<TcpStream, DenyAuthentication>,
-    proxy: Proxy,
-    timeout: Duration,
+    socket: &mut Socks5Socket<RouterStream, RouterAuthentication>,
+    options: &RouterOptions,
+    client: &Client,
 ) -> Result<(), SocksError> {
     match socket.get_command() {
         None => Err(ReplyError::CommandNotSupported.into()),
         Some(cmd) => match cmd {
-            Socks5Command::TCPBind => Err(ReplyError::CommandNotSupported.into()),
-            Socks5Command::TCPConnect => execute_command_connect(socket, proxy, timeout).await,
-            Socks5Command::UDPAssociate => Err(ReplyError::CommandNotSupported.into()),
+            Socks5Command::TCPBind => execute_command_bind(socket, options, client).await,
+            Socks5Command::TCPConnect => execute_command_connect(socket, options, client).await,
+            Socks5Command::UDPAssociate => {
+                execute_command_udp_associate(socket, options, client).await
+            }
         },
     }
 }
 
 async fn execute_command_connect(
-    socket: &mut Socks5Socket<TcpStream, DenyAuthentication>,
-    proxy: Proxy,
-    timeout: Duration,
+    socket: &mut Socks5Socket<RouterStream, RouterAuthentication>,
+    options: &RouterOptions,
+    client: &Client,
+) -> Result<(), SocksError> {
+    let (target_host, target_port) = requested_addr(socket)?;
+    let (_lease, mut downstream) =
+        connect_target(options, client, target_host, target_port).await?;
+
+    let bound_addr = downstream
+        .bound_addr()
+        .cloned()
+        .unwrap_or_else(|| TargetAddr::Ip(SocketAddr::from(([0, 0, 0, 0], 0))));
+
+    write_success_reply(socket, &bound_addr).await?;
+
+    Ok(transfer(&mut downstream, socket).await?)
+}
+
+async fn execute_command_bind(
+    socket: &mut Socks5Socket<RouterStream, RouterAuthentication>,
+    options: &RouterOptions,
+    client: &Client,
 ) -> Result<(), SocksError> {
-    let socket_addr = socket
-        .target_addr()
-        .context("Empty target address")?
-        .to_socket_addrs()?
-        .next()
-        .context("Unreachable target")?;
-
-    let mut downstream = match proxy
-        .connect_with_timeout(&socket_addr.ip().to_string(), socket_addr.port(), timeout)
+    let (target_host, target_port, allowed_host) = requested_target(socket, options).await?;
+    let session = client.session();
+    let lease = options
+        .pool_for(client.identity.as_ref())
+        .acquire_for_session(&session, &[], options.request_timeout)
         .await
+        .map_err(map_proxy_connect_error)?;
+    let upstream = lease.upstream().with_session(&session);
+    // Upstreams resolving the target here use the addresses checked by the policy
+    let target_host = match upstream.resolves_locally() {
+        true => allowed_host
+            .local_host(&target_host, upstream.resolves_ipv4_only())
+            .ok_or(ReplyError::ConnectionNotAllowed)?,
+        false => target_host,
+    };
+    let pending_bind = match tokio::time::timeout(
+        options.request_timeout,
+        upstream.bind(&target_host, target_port),
+    )
+    .await
     {
-        Ok(stream) => stream,
-        Err(err) => return Err(map_proxy_connect_error(err).into()),
+        Ok(Ok(pending_bind)) => pending_bind,
+        Ok(Err(err)) => {
+            error!("Can't bind on {}: {}", upstream, err);
+
+            return Err(map_proxy_connect_error(err).into());
+        }
+        Err(_) => return Err(ReplyError::ConnectionTimeout.into()),
     };
 
-    debug!("Connected to downstream proxy");
+    debug!("Bound on {} at {}", upstream, pending_bind.bound_addr());
 
-    socket
-        .write(&vec![
-            5, // protocol version = socks5
-            0, // reply code = succeeded
-            0, // reserved
-            1, // address type = ipv4
-            127, 0, 0, 1, // address = 127.0.0.1
-            0, 0, // port = 0
-        ])
+    // The first reply tells the client where the upstream waits for the incoming connection
+    write_success_reply(socket, pending_bind.bound_addr()).await?;
+
+    let mut control = [0u8; 64];
+    let (peer_addr, mut downstream) = tokio::select! {
+        res = pending_bind.accept() => match res {
+            Ok(res) => res,
+            Err(err) => return Err(map_proxy_connect_error(err).into()),
+        },
+        _ = socket.read(&mut control) => {
+            info!("BIND cancelled by client");
+            return Ok(());
+        }
+    };
+
+    debug!("Accepted BIND connection from {}", peer_addr);
+
+    // The second reply tells the client the address of the connected peer
+    write_success_reply(socket, &peer_addr).await?;
+
+    Ok(transfer(&mut downstream, socket).await?)
+}
+
+/// Returns the target of the client request
+fn requested_addr(
+    socket: &Socks5Socket<RouterStream, RouterAuthentication>,
+) -> Result<(String, u16), SocksError> {
+    match socket.target_addr().context("Empty target address")? {
+        TargetAddr::Ip(addr) => Ok((addr.ip().to_string(), addr.port())),
+        TargetAddr::Domain(domain, port) => Ok((domain.clone(), *port)),
+    }
+}
+
+/// Returns the target of the client request if the policy and the rules allow it, the hostname is resolved
+/// if local resolution is set
+async fn requested_target(
+    socket: &Socks5Socket<RouterStream, RouterAuthentication>,
+    options: &RouterOptions,
+) -> Result<(String, u16, AllowedHost), SocksError> {
+    let (target_host, target_port) = requested_addr(socket)?;
+    let resolved_host = resolve_target(options, target_host.clone(), target_port)
         .await
-        .context("Can't write successful reply")?;
-    socket.flush().await.context("Can't flush the reply")?;
+        .map_err(map_proxy_connect_error)?;
+    let allowed_host = options
+        .target_policy
+        .check_host(&resolved_host, target_port)
+        .await
+        .ok_or(ReplyError::ConnectionNotAllowed)?;
 
-    debug!("Start data transfer");
+    if let Some(err) = rejected_by_rules(options, &target_host, &resolved_host, target_port) {
+        debug!("Rejected {}:{} by the rules", target_host, target_port);
 
-    match tokio::io::copy_bidirectional(&mut downstream, socket).await {
-        Ok(res) => {
-            info!("Socket transfer finished ({}, {})", res.0, res.1);
-        }
-        Err(err) => match err.kind() {
-            ErrorKind::NotConnected => {
-                info!("Socket transfer closed by client");
-            }
-            ErrorKind::ConnectionReset => {
-                info!("Socket transfer closed by downstream proxy");
-            }
-            _ => return Err(err.into()),
-        },
+        return Err(err.into());
     }
 
-    Ok(())
+    Ok((resolved_host, target_port, allowed_host))
 }
 
-fn map_proxy_connect_error(err: ProxyError) -> ReplyError {
-    let mut io_error: Option<std::io::Error> = None;
+async fn execute_command_udp_associate(
+    socket: &mut Socks5Socket<RouterStream, RouterAuthentication>,
+    options: &RouterOptions,
+    client: &Client,
+) -> Result<(), SocksError> {
+    let session = client.session();
+    let lease = options
+        .pool_for(client.identity.as_ref())
+        .acquire_for_session(&session, &[], options.request_timeout)
+        .await
+        .map_err(map_proxy_connect_error)?;
+    let upstream = lease.upstream().with_session(&session);
+    let association =
+        match tokio::time::timeout(options.request_timeout, upstream.udp_associate()).await {
+            Ok(Ok(association)) => association,
+            Ok(Err(err)) => {
+                error!("Can't open UDP association on {}: {}", upstream, err);
+
+                return Err(map_proxy_connect_error(err).into());
+            }
+            Err(_) => return Err(ReplyError::ConnectionTimeout.into()),
+        };
+    // The relay is bound on the same interface the client is connected to
+    let relay = UdpSocket::bind(SocketAddr::new(client.local_addr.ip(), 0)).await?;
 
-    match err {
-        ProxyError::ConnectionTimeout => return ReplyError::ConnectionTimeout,
-        ProxyError::HttpError(HttpError::IoError(err)) => {
-            io_error = Some(err);
-        }
-        ProxyError::SocksError(SocksError::Io(err)) => {
-            io_error = Some(err);
-        }
-        _ => (),
-    };
+    debug!("Opened UDP association on {}", upstream);
+
+    write_success_reply(socket, &TargetAddr::Ip(relay.local_addr()?)).await?;
+
+    let mut client_udp_addr: Option<SocketAddr> = None;
+    // Both directions are polled at once, so each has its own buffer
+    let mut datagram = vec![0u8; MAX_DATAGRAM_SIZE];
+    let mut upstream_datagram = vec![0u8; MAX_DATAGRAM_SIZE];
+    let mut control = [0u8; 64];
+
+    loop {
+        tokio::select! {
+            res = relay.recv_from(&mut datagram) => {
+                let (len, from) = res?;
+
+                // Datagrams are accepted only from the host of the client
+                if from.ip() != client.peer_addr.ip() {
+                    continue;
+                }
+
+                client_udp_addr = Some(from);
+
+                let (target_addr, payload) = match decode_udp_datagram(&datagram[..len]) {
+                    Some((0, target_addr, payload)) => (target_addr, payload),
+                    // Fragmentation is not supported, such datagrams are dropped
+                    _ => continue,
+                };
+                let (requested_host, requested_port) = match &target_addr {
+                    TargetAddr::Ip(addr) => (addr.ip().to_string(), addr.port()),
+                    TargetAddr::Domain(domain, port) => (domain.clone(), *port),
+                };
+                let target_addr = match (options.dns_resolution, target_addr) {
+                    (DnsResolution::Local, TargetAddr::Domain(domain, port)) => {
+                        match resolve_host(&domain, port).await {
+                            Ok(addr) => TargetAddr::Ip(addr),
+                            Err(err) => {
+                                debug!("Dropped UDP datagram to {}: {}", domain, err);
+                                continue;
+                            }
+                        }
+                    }
+                    (_, target_addr) => target_addr,
+                };
+                let resolved_host = match &target_addr {
+                    TargetAddr::Ip(addr) => addr.ip().to_string(),
+                    TargetAddr::Domain(domain, _) => domain.clone(),
+                };
+
+                if rejected_by_rules(options, &requested_host, &resolved_host, requested_port).is_some() {
+                    debug!("Dropped UDP datagram to {}: rejected by the rules", requested_host);
+                    continue;
+                }
 
-    if io_error.is_some() {
-        match io_error.unwrap().kind() {
-            ErrorKind::ConnectionRefused => {
-                return ReplyError::ConnectionRefused;
+                let allowed_target_addr = match target_addr {
+                    TargetAddr::Ip(addr) => {
+                        options.target_policy.is_allowed(addr.ip()).then_some(TargetAddr::Ip(addr))
+                    }
+                    TargetAddr::Domain(domain, port) => {
+                        match options.target_policy.check_host(&domain, port).await {
+                            // The association resolving the target here sends to the checked address
+                            Some(allowed_host) if association.resolves_locally() => allowed_host
+                                .local_host(&domain, false)
+                                .map(|host| socks5_target_addr(&host, port)),
+                            Some(_) => Some(TargetAddr::Domain(domain, port)),
+                            None => None,
+                        }
+                    }
+                };
+                let target_addr = match allowed_target_addr {
+                    Some(target_addr) => target_addr,
+                    None => {
+                        debug!("Dropped UDP datagram: denied by the target policy");
+                        continue;
+                    }
+                };
+
+                if let Err(err) = association.send_to(&target_addr, payload).await {
+                    debug!("Dropped UDP datagram to {}: {}", target_addr, err);
+                }
+            }
+            res = association.recv_from(&mut upstream_datagram) => {
+                let (source_addr, payload) = match res {
+                    Ok(res) => res,
+                    Err(err) => {
+                        info!("UDP association closed by downstream proxy: {}", err);
+                        break;
+                    }
+                };
+
+                if let Some(client_udp_addr) = client_udp_addr {
+                    relay
+                        .send_to(&encode_udp_datagram(&source_addr, payload), client_udp_addr)
+                        .await?;
+                }
             }
-            ErrorKind::ConnectionAborted => {
-                return ReplyError::ConnectionNotAllowed;
+            res = socket.read(&mut control) => {
+                // The association ends when the client closes the control connection
+                if let Ok(0) | Err(_) = res {
+                    info!("UDP association closed by client");
+                    break;
+                }
             }
-            ErrorKind::ConnectionReset => {
-                return ReplyError::ConnectionNotAllowed;
+            _ = association.closed() => {
+                info!("UDP association closed by downstream proxy");
+                break;
             }
-            ErrorKind::NotConnected => return ReplyError::NetworkUnreachable,
-            _ => (),
         }
     }
 
-    return ReplyError::GeneralFailure;
+    Ok(())
+}
+
+async fn write_success_reply(
+    socket: &mut Socks5Socket<RouterStream, RouterAuthentication>,
+    bound_addr: &TargetAddr,
+) -> Result<(), SocksError> {
+    let mut reply = vec![
+        5, // protocol version = socks5
+        0, // reply code = succeeded
+        0, // reserved
+    ];
+
+    encode_addr(bound_addr, &mut reply);
+
+    socket
+        .write_all(&reply)
+        .await
+        .context("Can't write successful reply")?;
+    socket.flush().await.context("Can't flush the reply")?;
+
+    Ok(())
 }